@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN password_hash VARCHAR NOT NULL DEFAULT '';
+ALTER TABLE users ALTER COLUMN password_hash DROP DEFAULT;
\ No newline at end of file
@@ -0,0 +1,2 @@
+ALTER TABLE users ADD COLUMN role VARCHAR NOT NULL DEFAULT 'User'
+    CONSTRAINT users_role_check CHECK (role IN ('User', 'Admin'));
\ No newline at end of file
@@ -0,0 +1,3 @@
+DROP TABLE character_episodes;
+DROP TABLE character_friends;
+DROP TABLE characters;
\ No newline at end of file
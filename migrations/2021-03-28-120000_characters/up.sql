@@ -0,0 +1,61 @@
+CREATE TABLE characters (
+  id VARCHAR PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  kind VARCHAR NOT NULL CONSTRAINT characters_kind_check CHECK (kind IN ('human', 'droid')),
+  home_planet VARCHAR,
+  primary_function VARCHAR,
+  CONSTRAINT characters_home_planet_check CHECK (kind = 'human' OR home_planet IS NULL),
+  CONSTRAINT characters_primary_function_check CHECK (kind = 'droid' OR primary_function IS NULL)
+);
+
+-- a friendship is stored once from each side
+CREATE TABLE character_friends (
+  character_id VARCHAR NOT NULL REFERENCES characters (id) ON DELETE CASCADE,
+  friend_id VARCHAR NOT NULL REFERENCES characters (id) ON DELETE CASCADE,
+  PRIMARY KEY (character_id, friend_id),
+  CONSTRAINT character_friends_self_check CHECK (character_id <> friend_id)
+);
+
+CREATE INDEX character_friends_friend_id_idx ON character_friends (friend_id);
+
+CREATE TABLE character_episodes (
+  character_id VARCHAR NOT NULL REFERENCES characters (id) ON DELETE CASCADE,
+  episode VARCHAR NOT NULL CONSTRAINT character_episodes_episode_check
+    CHECK (episode IN ('NEW_HOPE', 'EMPIRE', 'JEDI')),
+  PRIMARY KEY (character_id, episode)
+);
+
+-- the same characters as data/starwars.json
+INSERT INTO characters (id, name, kind, home_planet, primary_function) VALUES
+  ('1000', 'Luke Skywalker', 'human', 'Tatooine', NULL),
+  ('1001', 'Darth Vader', 'human', 'Tatooine', NULL),
+  ('1002', 'Han Solo', 'human', NULL, NULL),
+  ('1003', 'Leia Organa', 'human', 'Alderaan', NULL),
+  ('1004', 'Wilhuff Tarkin', 'human', NULL, NULL),
+  ('2000', 'C-3PO', 'droid', NULL, 'Protocol'),
+  ('2001', 'R2-D2', 'droid', NULL, 'Astromech');
+
+WITH friendships (a, b) AS (VALUES
+  ('1000', '1002'),
+  ('1000', '1003'),
+  ('1000', '2000'),
+  ('1000', '2001'),
+  ('1001', '1004'),
+  ('1002', '1003'),
+  ('1002', '2000'),
+  ('1002', '2001'),
+  ('1003', '2000'),
+  ('1003', '2001'),
+  ('2000', '2001')
+)
+INSERT INTO character_friends (character_id, friend_id)
+  SELECT a, b FROM friendships
+  UNION ALL
+  SELECT b, a FROM friendships;
+
+INSERT INTO character_episodes (character_id, episode)
+  SELECT id, episode
+  FROM characters, (VALUES ('NEW_HOPE'), ('EMPIRE'), ('JEDI')) AS episodes (episode)
+  WHERE id <> '1004';
+
+INSERT INTO character_episodes (character_id, episode) VALUES ('1004', 'NEW_HOPE');
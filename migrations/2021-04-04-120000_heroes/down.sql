@@ -0,0 +1 @@
+DROP TABLE heroes;
\ No newline at end of file
@@ -0,0 +1,16 @@
+-- the row without an episode is the hero of the whole saga,
+-- the others override it for their episode, like `hero` and `episodeHeroes` in data/starwars.json
+-- no cascade, a hero can not be deleted
+CREATE TABLE heroes (
+  id SERIAL PRIMARY KEY,
+  episode VARCHAR UNIQUE CONSTRAINT heroes_episode_check
+    CHECK (episode IN ('NEW_HOPE', 'EMPIRE', 'JEDI')),
+  character_id VARCHAR NOT NULL REFERENCES characters (id)
+);
+
+-- unique ignores NULLs, this keeps the saga to one hero
+CREATE UNIQUE INDEX heroes_saga_idx ON heroes ((episode IS NULL)) WHERE episode IS NULL;
+
+INSERT INTO heroes (episode, character_id) VALUES
+  (NULL, '2001'),
+  ('EMPIRE', '1000');
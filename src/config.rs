@@ -0,0 +1,468 @@
+use darpi::chrono::Duration;
+use derive_more::Display;
+use jsonwebtoken::Algorithm;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const DEFAULT_CONFIG_FILE: &str = "config.toml";
+// room for the bigger graphql documents, it applies to every route
+pub const DEFAULT_BODY_LIMIT: u64 = 16 * 1024;
+
+// every key we understand
+// with the env var that overrides it
+const KEYS: &[(&str, &str)] = &[
+    ("host", "HOST"),
+    ("port", "PORT"),
+    ("log_level", "LOG_LEVEL"),
+    ("body_limit", "BODY_LIMIT"),
+    ("storage", "STORAGE"),
+    ("database.url", "DATABASE_URL"),
+    ("database.pool_size", "DATABASE_POOL_SIZE"),
+    (
+        "database.connection_timeout_secs",
+        "DATABASE_CONNECTION_TIMEOUT_SECS",
+    ),
+    ("jwt.secret", "JWT_SECRET"),
+    ("jwt.algorithm", "JWT_ALGORITHM"),
+    ("jwt.key_id", "JWT_KEY_ID"),
+    ("jwt.private_key", "JWT_PRIVATE_KEY"),
+    ("jwt.public_key", "JWT_PUBLIC_KEY"),
+    ("jwt.previous_keys_dir", "JWT_PREVIOUS_KEYS_DIR"),
+    ("jwt.expiry_secs", "JWT_EXPIRY_SECS"),
+    ("jwt.refresh_expiry_secs", "JWT_REFRESH_EXPIRY_SECS"),
+    ("graphql.max_depth", "GRAPHQL_MAX_DEPTH"),
+    ("graphql.max_complexity", "GRAPHQL_MAX_COMPLEXITY"),
+    ("graphql.timeout_secs", "GRAPHQL_TIMEOUT_SECS"),
+    ("graphql.apq_cache_size", "GRAPHQL_APQ_CACHE_SIZE"),
+    ("graphql.allowlist_dir", "GRAPHQL_ALLOWLIST_DIR"),
+    ("graphql.introspection", "GRAPHQL_INTROSPECTION"),
+    ("graphql.playground", "GRAPHQL_PLAYGROUND"),
+    ("starwars.data_file", "STARWARS_DATA_FILE"),
+    ("starwars.reload_secs", "STARWARS_RELOAD_SECS"),
+];
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub address: String,
+    pub log_level: String,
+    // max request body size in bytes
+    pub body_limit: u64,
+    pub storage: StorageConfig,
+    pub jwt: JwtConfig,
+    pub graphql: GraphqlConfig,
+    pub starwars: StarWarsConfig,
+}
+
+#[derive(Debug, Clone)]
+pub enum StorageConfig {
+    Postgres(DatabaseConfig),
+    // nothing survives a restart
+    // for local development and tests without a database service
+    Memory,
+}
+
+#[derive(Debug, Clone)]
+pub struct DatabaseConfig {
+    pub url: String,
+    pub pool_size: u32,
+    pub connection_timeout: std::time::Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    pub algorithm: Algorithm,
+    // the `kid` header of the tokens we sign
+    pub key_id: String,
+    pub keys: JwtKeyConfig,
+    // of the access tokens, keep it short
+    pub expiry: Duration,
+    pub refresh_expiry: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct GraphqlConfig {
+    // queries over a limit are rejected before they run
+    pub max_depth: usize,
+    pub max_complexity: usize,
+    // of a single query or mutation, subscriptions run for as long as they like
+    pub timeout: std::time::Duration,
+    // how many automatic persisted queries are remembered
+    pub apq_cache_size: usize,
+    // when set, only the documents in it can be executed
+    pub allowlist_dir: Option<PathBuf>,
+    // `__schema` and `__type`, turn it off in production
+    pub introspection: bool,
+    // the in-browser ide on /starwars/playground, for development only
+    pub playground: bool,
+}
+
+#[derive(Debug, Clone)]
+// only the memory storage reads the characters from a file
+// postgres has them in its tables
+pub struct StarWarsConfig {
+    // json, or yaml when it ends in .yaml or .yml
+    pub data_file: PathBuf,
+    // when set, the file is checked this often and reloaded when it changed
+    pub reload: Option<std::time::Duration>,
+}
+
+#[derive(Clone)]
+pub enum JwtKeyConfig {
+    // HS256, HS384, HS512
+    Secret(String),
+    // RS256, RS384, RS512, ES256
+    Pem {
+        private_key: PathBuf,
+        public_key: PathBuf,
+        // public keys we stopped signing with but still accept
+        // each file is named `<kid>.pem`, or `<kid>.<algorithm>.pem` when it is not `algorithm`
+        previous_keys_dir: Option<PathBuf>,
+    },
+}
+
+// keep the secret out of the logs
+impl fmt::Debug for JwtKeyConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Secret(_) => f.debug_tuple("Secret").field(&"***").finish(),
+            Self::Pem {
+                private_key,
+                public_key,
+                previous_keys_dir,
+            } => f
+                .debug_struct("Pem")
+                .field("private_key", private_key)
+                .field("public_key", public_key)
+                .field("previous_keys_dir", previous_keys_dir)
+                .finish(),
+        }
+    }
+}
+
+// collects every problem instead of stopping at the first one
+// so a broken deployment can be fixed in one go
+#[derive(Debug)]
+pub struct ConfigError(Vec<String>);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Display, Clone, Copy)]
+enum Source {
+    #[display(fmt = "default")]
+    Default,
+    #[display(fmt = "config file")]
+    File,
+    #[display(fmt = "env var {}", _0)]
+    Env(&'static str),
+}
+
+// the merged view of every layer
+// later layers win: defaults, then the file, then the environment
+struct Layers {
+    values: HashMap<&'static str, (String, Source)>,
+    problems: Vec<String>,
+}
+
+impl Layers {
+    fn new() -> Self {
+        let mut layers = Self {
+            values: HashMap::new(),
+            problems: vec![],
+        };
+
+        layers.set("host", "0.0.0.0", Source::Default);
+        layers.set("log_level", "info", Source::Default);
+        layers.set(
+            "body_limit",
+            &DEFAULT_BODY_LIMIT.to_string(),
+            Source::Default,
+        );
+        layers.set("storage", "postgres", Source::Default);
+        layers.set("database.pool_size", "10", Source::Default);
+        layers.set("database.connection_timeout_secs", "30", Source::Default);
+        layers.set("jwt.algorithm", "HS256", Source::Default);
+        layers.set("jwt.key_id", "default", Source::Default);
+        // 15 minutes
+        layers.set("jwt.expiry_secs", "900", Source::Default);
+        // 30 days
+        layers.set("jwt.refresh_expiry_secs", "2592000", Source::Default);
+        layers.set("graphql.max_depth", "10", Source::Default);
+        layers.set("graphql.max_complexity", "1000", Source::Default);
+        layers.set("graphql.timeout_secs", "10", Source::Default);
+        layers.set("graphql.apq_cache_size", "1000", Source::Default);
+        layers.set("graphql.introspection", "true", Source::Default);
+        layers.set("graphql.playground", "false", Source::Default);
+        layers.set("starwars.data_file", "data/starwars.json", Source::Default);
+
+        layers
+    }
+
+    fn set(&mut self, key: &str, value: &str, source: Source) {
+        match KEYS.iter().find(|(k, _)| *k == key) {
+            Some(&(key, _)) => {
+                self.values.insert(key, (value.to_string(), source));
+            }
+            None => self
+                .problems
+                .push(format!("{}: unknown key `{}`", source, key)),
+        }
+    }
+
+    fn file(&mut self, path: &Path) {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) => {
+                self.problems
+                    .push(format!("could not read {}: {}", path.display(), e));
+                return;
+            }
+        };
+
+        match content.parse::<toml::Value>() {
+            Ok(toml::Value::Table(table)) => self.table("", table),
+            Ok(_) => self
+                .problems
+                .push(format!("{}: expected a table", path.display())),
+            Err(e) => self
+                .problems
+                .push(format!("could not parse {}: {}", path.display(), e)),
+        }
+    }
+
+    fn table(&mut self, prefix: &str, table: toml::value::Table) {
+        for (name, value) in table {
+            let key = format!("{}{}", prefix, name);
+            match value {
+                toml::Value::Table(inner) => self.table(&format!("{}.", key), inner),
+                toml::Value::String(s) => self.set(&key, &s, Source::File),
+                toml::Value::Integer(i) => self.set(&key, &i.to_string(), Source::File),
+                toml::Value::Boolean(b) => self.set(&key, &b.to_string(), Source::File),
+                toml::Value::Float(f) => self.set(&key, &f.to_string(), Source::File),
+                _ => self
+                    .problems
+                    .push(format!("config file: `{}` must be a scalar", key)),
+            }
+        }
+    }
+
+    fn env(&mut self) {
+        for &(key, var) in KEYS {
+            if let Ok(value) = std::env::var(var) {
+                self.set(key, &value, Source::Env(var));
+            }
+        }
+    }
+
+    fn optional<T: FromStr>(&mut self, key: &'static str) -> Option<T>
+    where
+        T::Err: fmt::Display,
+    {
+        if self.values.contains_key(key) {
+            self.required(key)
+        } else {
+            None
+        }
+    }
+
+    fn required<T: FromStr>(&mut self, key: &'static str) -> Option<T>
+    where
+        T::Err: fmt::Display,
+    {
+        let (value, source) = match self.values.get(key) {
+            Some(v) => v,
+            None => {
+                let var = KEYS
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, var)| *var)
+                    .unwrap_or_default();
+                self.problems.push(format!(
+                    "`{}` is missing (set {} or add it to the config file)",
+                    key, var
+                ));
+                return None;
+            }
+        };
+
+        match value.parse() {
+            Ok(v) => Some(v),
+            Err(e) => {
+                let problem = format!("`{}` from {} is invalid: {}", key, source, e);
+                self.problems.push(problem);
+                None
+            }
+        }
+    }
+
+    // for the sizes and durations where 0 would quietly turn a feature off
+    fn positive<T: FromStr + Default + PartialOrd + fmt::Display>(
+        &mut self,
+        key: &'static str,
+    ) -> Option<T>
+    where
+        T::Err: fmt::Display,
+    {
+        let value: T = self.required(key)?;
+        if value <= T::default() {
+            let source = self.values[key].1;
+            self.problems.push(format!(
+                "`{}` from {} must be greater than 0, got {}",
+                key, source, value
+            ));
+            return None;
+        }
+
+        Some(value)
+    }
+}
+
+impl Config {
+    // loads `.env`, then the file named by CONFIG_FILE (or ./config.toml if present),
+    // then lets the environment override any of it
+    pub fn load() -> Result<Self, ConfigError> {
+        dotenv::dotenv().ok();
+
+        let mut layers = Layers::new();
+
+        match std::env::var("CONFIG_FILE") {
+            Ok(path) => layers.file(Path::new(&path)),
+            Err(_) if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                layers.file(Path::new(DEFAULT_CONFIG_FILE))
+            }
+            Err(_) => {}
+        }
+
+        layers.env();
+
+        let host: Option<String> = layers.required("host");
+        let port: Option<u16> = layers.required("port");
+        let log_level = layers.required("log_level");
+        let body_limit = layers.required("body_limit");
+        let storage = layers
+            .required::<String>("storage")
+            .and_then(|s| storage(&mut layers, &s));
+        let algorithm = layers
+            .required::<String>("jwt.algorithm")
+            .and_then(|a| jwt_algorithm(&a).map_err(|e| layers.problems.push(e)).ok());
+        let key_id = layers.required("jwt.key_id");
+        let keys = algorithm.and_then(|a| jwt_keys(&mut layers, a));
+        let expiry = layers.positive("jwt.expiry_secs");
+        let refresh_expiry = layers.positive("jwt.refresh_expiry_secs");
+        let max_depth = layers.required("graphql.max_depth");
+        let max_complexity = layers.required("graphql.max_complexity");
+        let timeout = layers.required("graphql.timeout_secs");
+        let apq_cache_size = layers.positive("graphql.apq_cache_size");
+        let allowlist_dir = layers.optional("graphql.allowlist_dir");
+        let introspection = layers.required("graphql.introspection");
+        let playground = layers.required("graphql.playground");
+        let data_file = layers.required("starwars.data_file");
+        let reload = layers.optional("starwars.reload_secs");
+
+        if !layers.problems.is_empty() {
+            return Err(ConfigError(layers.problems));
+        }
+
+        // every field is `Some` once there are no problems
+        Ok(Self {
+            address: format!("{}:{}", host.unwrap(), port.unwrap()),
+            log_level: log_level.unwrap(),
+            body_limit: body_limit.unwrap(),
+            storage: storage.unwrap(),
+            jwt: JwtConfig {
+                algorithm: algorithm.unwrap(),
+                key_id: key_id.unwrap(),
+                keys: keys.unwrap(),
+                expiry: Duration::seconds(expiry.unwrap()),
+                refresh_expiry: Duration::seconds(refresh_expiry.unwrap()),
+            },
+            graphql: GraphqlConfig {
+                max_depth: max_depth.unwrap(),
+                max_complexity: max_complexity.unwrap(),
+                timeout: std::time::Duration::from_secs(timeout.unwrap()),
+                apq_cache_size: apq_cache_size.unwrap(),
+                allowlist_dir,
+                introspection: introspection.unwrap(),
+                playground: playground.unwrap(),
+            },
+            starwars: StarWarsConfig {
+                data_file: data_file.unwrap(),
+                reload: reload.map(std::time::Duration::from_secs),
+            },
+        })
+    }
+}
+
+// the database keys only matter for postgres
+fn storage(layers: &mut Layers, name: &str) -> Option<StorageConfig> {
+    match name {
+        "postgres" => {
+            let url = layers.required("database.url");
+            let pool_size = layers.required("database.pool_size");
+            let timeout = layers.required("database.connection_timeout_secs");
+            Some(StorageConfig::Postgres(DatabaseConfig {
+                url: url?,
+                pool_size: pool_size?,
+                connection_timeout: std::time::Duration::from_secs(timeout?),
+            }))
+        }
+        "memory" => Some(StorageConfig::Memory),
+        _ => {
+            layers.problems.push(format!(
+                "`storage` must be one of postgres, memory, got `{}`",
+                name
+            ));
+            None
+        }
+    }
+}
+
+fn jwt_algorithm(name: &str) -> Result<Algorithm, String> {
+    use Algorithm::*;
+
+    match Algorithm::from_str(name) {
+        Ok(a @ HS256) | Ok(a @ HS384) | Ok(a @ HS512) => Ok(a),
+        Ok(a @ RS256) | Ok(a @ RS384) | Ok(a @ RS512) | Ok(a @ ES256) => Ok(a),
+        _ => Err(format!(
+            "`jwt.algorithm` must be one of HS256, HS384, HS512, RS256, RS384, RS512, ES256, got `{}`",
+            name
+        )),
+    }
+}
+
+// HMAC needs a shared secret, everything else a PEM key pair
+fn jwt_keys(layers: &mut Layers, algorithm: Algorithm) -> Option<JwtKeyConfig> {
+    match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            let secret: String = layers.required("jwt.secret")?;
+            if secret.is_empty() {
+                layers
+                    .problems
+                    .push("`jwt.secret` must not be empty".to_string());
+                return None;
+            }
+            Some(JwtKeyConfig::Secret(secret))
+        }
+        _ => {
+            let private_key = layers.required("jwt.private_key");
+            let public_key = layers.required("jwt.public_key");
+            let previous_keys_dir = layers.optional("jwt.previous_keys_dir");
+            Some(JwtKeyConfig::Pem {
+                private_key: private_key?,
+                public_key: public_key?,
+                previous_keys_dir,
+            })
+        }
+    }
+}
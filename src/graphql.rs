@@ -0,0 +1,228 @@
+use crate::jwt::Claims;
+use crate::middleware::{require_role, Role};
+use crate::models::{NewUser, User, UserError};
+use crate::repository::UserRepository;
+use async_graphql::connection::{query, Connection, Edge, EmptyFields};
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::guard::Guard;
+use async_graphql::{Context, Data, ErrorExtensions, Object};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+// the longest page any connection returns
+pub(crate) const MAX_PAGE_SIZE: usize = 100;
+
+// what a list field is charged, times the cost of one item
+// capped like the page itself, so the product of nested pages stays small
+pub(crate) fn list_cost(requested: Option<i32>, default: usize) -> usize {
+    requested
+        .map_or(default, |n| n.max(0) as usize)
+        .min(MAX_PAGE_SIZE)
+}
+
+// remembers what the loader it wraps returned, so every key is loaded once
+// the loaders are made for each request, so this is a request-scoped cache
+pub struct CachedLoader<K, V, L> {
+    loader: L,
+    // none when the data lives as long as a websocket, the cache would go stale
+    entries: Option<Mutex<HashMap<K, V>>>,
+}
+
+impl<K, V, L> CachedLoader<K, V, L> {
+    // forgets everything, for after a mutation
+    pub fn clear(&self) {
+        if let Some(entries) = &self.entries {
+            entries.lock().expect("loader cache poisoned").clear();
+        }
+    }
+}
+
+#[async_trait]
+impl<K, L> Loader<K> for CachedLoader<K, L::Value, L>
+where
+    K: Send + Sync + Hash + Eq + Clone + 'static,
+    L: Loader<K>,
+{
+    type Value = L::Value;
+    type Error = L::Error;
+
+    async fn load(&self, keys: &[K]) -> Result<HashMap<K, Self::Value>, Self::Error> {
+        let entries = match &self.entries {
+            Some(entries) => entries,
+            None => return self.loader.load(keys).await,
+        };
+
+        let mut found = HashMap::with_capacity(keys.len());
+        let mut missing = Vec::new();
+        {
+            let cached = entries.lock().expect("loader cache poisoned");
+            for key in keys {
+                match cached.get(key) {
+                    Some(value) => {
+                        found.insert(key.clone(), value.clone());
+                    }
+                    None => missing.push(key.clone()),
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            let loaded = self.loader.load(&missing).await?;
+            entries.lock().expect("loader cache poisoned").extend(
+                loaded
+                    .iter()
+                    .map(|(key, value)| (key.clone(), value.clone())),
+            );
+            found.extend(loaded);
+        }
+
+        Ok(found)
+    }
+}
+
+// a loader for one request, `cache: false` for a websocket connection
+pub(crate) fn data_loader<K, L>(loader: L, cache: bool) -> DataLoader<CachedLoader<K, L::Value, L>>
+where
+    K: Send + Sync + Hash + Eq + Clone + 'static,
+    L: Loader<K>,
+{
+    DataLoader::new(CachedLoader {
+        loader,
+        entries: if cache {
+            Some(Mutex::new(HashMap::new()))
+        } else {
+            None
+        },
+    })
+}
+
+// batches the users that one request resolves by id into a single lookup
+pub struct UsersById {
+    users: Arc<dyn UserRepository>,
+}
+
+#[async_trait]
+impl Loader<i32> for UsersById {
+    type Value = User;
+    type Error = async_graphql::Error;
+
+    async fn load(&self, ids: &[i32]) -> Result<HashMap<i32, User>, Self::Error> {
+        let users = self.users.find_by_ids(ids).await.map_err(|e| e.extend())?;
+        Ok(users.into_iter().map(|user| (user.id, user)).collect())
+    }
+}
+
+pub type UserLoader = DataLoader<CachedLoader<i32, User, UsersById>>;
+
+// what every operation can reach through its `Context`
+// the claims are there only when the request had a valid bearer token
+pub(crate) fn request_data(
+    users: Arc<dyn UserRepository>,
+    claims: Option<Claims>,
+    cache: bool,
+) -> Data {
+    let mut data = Data::default();
+    data.insert(data_loader(
+        UsersById {
+            users: users.clone(),
+        },
+        cache,
+    ));
+    data.insert(users);
+    if let Some(claims) = claims {
+        data.insert(claims);
+    }
+    data
+}
+
+// the graphql counterpart of the `authorize` middleware
+// a field with `#[graphql(guard(RoleGuard(role = "Role::Admin")))]`
+// resolves only for a token with that role or a higher one
+pub struct RoleGuard {
+    pub role: Role,
+}
+
+#[async_trait]
+impl Guard for RoleGuard {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        require_role(ctx.data_opt::<Claims>(), self.role).map_err(|e| e.extend())
+    }
+}
+
+fn user_repository(ctx: &Context<'_>) -> Arc<dyn UserRepository> {
+    ctx.data_unchecked::<Arc<dyn UserRepository>>().clone()
+}
+
+#[derive(Default)]
+pub struct UserQuery;
+
+#[Object]
+impl UserQuery {
+    /// Like `users`, only for signed in users.
+    #[graphql(guard(RoleGuard(role = "Role::User")))]
+    async fn user(&self, ctx: &Context<'_>, id: i32) -> async_graphql::Result<Option<User>> {
+        ctx.data_unchecked::<UserLoader>().load_one(id).await
+    }
+
+    /// Any signed in user can browse the users.
+    #[graphql(
+        guard(RoleGuard(role = "Role::User")),
+        complexity = "list_cost(first, DEFAULT_PAGE_SIZE).saturating_mul(child_complexity)"
+    )]
+    async fn users(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Connection<usize, User, EmptyFields, EmptyFields>> {
+        let users = user_repository(ctx);
+
+        query(after, None, first, None, |after, _, first, _| async move {
+            // the cursor comes from the client and has to fit the offset of the storage
+            let offset = match after {
+                Some(after) => after
+                    .checked_add(1)
+                    .filter(|&offset| offset <= i64::MAX as usize)
+                    .ok_or("invalid cursor")?,
+                None => 0,
+            };
+            let limit = first.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+
+            // one more than asked for tells if there is a next page
+            let mut page = users
+                .list(limit as i64 + 1, offset as i64)
+                .await
+                .map_err(|e| e.extend())?;
+            let has_next_page = page.len() > limit;
+            page.truncate(limit);
+
+            let mut connection = Connection::new(offset > 0, has_next_page);
+            connection.append(
+                page.into_iter()
+                    .enumerate()
+                    .map(|(idx, user)| Edge::new(offset + idx, user)),
+            );
+            Ok::<_, async_graphql::Error>(connection)
+        })
+        .await
+    }
+}
+
+#[derive(Default)]
+pub struct UserMutation;
+
+#[Object]
+impl UserMutation {
+    /// Admins only, like `POST /user`.
+    #[graphql(guard(RoleGuard(role = "Role::Admin")))]
+    async fn create_user(&self, ctx: &Context<'_>, input: NewUser) -> async_graphql::Result<User> {
+        let new_user = input.validate().map_err(|e| UserError::from(e).extend())?;
+        user_repository(ctx)
+            .create(new_user)
+            .await
+            .map_err(|e| e.extend())
+    }
+}
@@ -1,9 +1,10 @@
-use super::{Container, DbPoolGetter};
-use crate::middleware::{roundtrip, Role};
-use crate::models::{self, NewUser, User, UserError};
-use darpi::job::IOBlockingJob;
-use darpi::{chrono::Duration, handler, Json, Path, Query};
-use darpi_middleware::{auth::*, body_size_limit};
+use super::Container;
+use crate::jwt::{Claims, JwkSet, JwtKeys};
+use crate::middleware::{authorize, roundtrip, Role};
+use crate::models::{NewUser, User, UserError, UserUpdate};
+use crate::repository::{RefreshTokenRepository, UserRepository};
+use crate::validation;
+use darpi::{handler, Json, Path, Query};
 use log::warn;
 use serde::{Deserialize, Serialize};
 use std::sync::Arc;
@@ -14,27 +15,107 @@ pub struct Login {
     password: String,
 }
 
+#[derive(Serialize, Debug)]
+pub struct TokenPair {
+    access_token: String,
+    refresh_token: String,
+    token_type: &'static str,
+    // seconds until the access token expires
+    expires_in: i64,
+}
+
+fn token_pair(
+    jwt_keys: &dyn JwtKeys,
+    user: &User,
+    refresh_token: String,
+) -> Result<TokenPair, UserError> {
+    let expiry = jwt_keys.expiry();
+    let claims = Claims::new(&user.id.to_string(), user.role, expiry);
+    let access_token = jwt_keys.sign(&claims).map_err(|e| {
+        warn!("could not create a token: {}", e);
+        UserError::InternalError
+    })?;
+
+    Ok(TokenPair {
+        access_token,
+        refresh_token,
+        token_type: "Bearer",
+        expires_in: expiry.num_seconds(),
+    })
+}
+
 // here we give the container type
 // so the framework knows where to get
-// the requested `Arc<dyn JwtTokenCreator>` from
+// the requested `Arc<dyn JwtKeys>` from
 #[handler({
     container: Container
 })]
 pub(crate) async fn login(
-    #[body] _login_data: Json<Login>,
-    #[inject] jwt_tok_creator: Arc<dyn JwtTokenCreator>,
-) -> Result<Token, Error> {
-    let admin = Role::Admin; // hardcoded just for the example
-    let uid = "uid"; // hardcoded just for the example
-    let tok = jwt_tok_creator
-        .create(uid, &admin, Duration::days(30))
-        .await
-        .map_err(|e| {
-            warn!("could not create a token: {}", e);
-            e
-        })?;
-
-    Ok(tok)
+    #[body] login_data: Json<Login>,
+    #[inject] users: Arc<dyn UserRepository>,
+    #[inject] refresh_tokens: Arc<dyn RefreshTokenRepository>,
+    #[inject] jwt_keys: Arc<dyn JwtKeys>,
+) -> Result<Json<TokenPair>, UserError> {
+    let Login { email, password } = login_data.into_inner();
+    let email = validation::normalize_email(&email);
+
+    let user = users
+        .authenticate(email, password)
+        .await?
+        .ok_or(UserError::InvalidCredentials)?;
+    let refresh = refresh_tokens
+        .create(user.id, jwt_keys.refresh_expiry())
+        .await?;
+
+    Ok(Json(token_pair(jwt_keys.as_ref(), &user, refresh)?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RefreshRequest {
+    refresh_token: String,
+}
+
+// the presented refresh token is spent
+// and replaced by the one in the response
+#[handler({
+    container: Container
+})]
+pub(crate) async fn refresh_token(
+    #[body] refresh: Json<RefreshRequest>,
+    #[inject] refresh_tokens: Arc<dyn RefreshTokenRepository>,
+    #[inject] jwt_keys: Arc<dyn JwtKeys>,
+) -> Result<Json<TokenPair>, UserError> {
+    let raw = refresh.into_inner().refresh_token;
+    let (user, refresh) = refresh_tokens
+        .rotate(raw, jwt_keys.refresh_expiry())
+        .await?;
+
+    Ok(Json(token_pair(jwt_keys.as_ref(), &user, refresh)?))
+}
+
+// revokes the refresh token and every token rotated from the same login
+// access tokens already issued stay valid until they expire
+#[handler({
+    container: Container
+})]
+pub(crate) async fn logout(
+    #[body] refresh: Json<RefreshRequest>,
+    #[inject] refresh_tokens: Arc<dyn RefreshTokenRepository>,
+) -> Result<String, UserError> {
+    refresh_tokens
+        .revoke(refresh.into_inner().refresh_token)
+        .await?;
+
+    Ok("logged out".to_string())
+}
+
+// the public keys clients can verify our tokens with
+// empty when tokens are signed with a shared secret
+#[handler({
+    container: Container
+})]
+pub(crate) async fn jwks(#[inject] jwt_keys: Arc<dyn JwtKeys>) -> Json<JwkSet> {
+    Json(jwt_keys.jwks().clone())
 }
 
 #[derive(Deserialize, Serialize, Debug, Query, Path)]
@@ -49,32 +130,21 @@ pub(crate) async fn home() -> String {
 
 // here we give the container type
 // so the framework knows where to get
-// the requested `Arc<dyn DbPoolGetter>` from
-// enforce max request body size 128 bytes and admin role via middleware
+// the requested `Arc<dyn UserRepository>` from
+// the body size is limited for every route, the admin role is enforced via middleware
 #[handler({
     container: Container,
     middleware: {
-        request: [roundtrip("my string"), body_size_limit(128), authorize(Role::Admin)]
+        request: [roundtrip("my string"), authorize(Role::Admin)]
     }
 })]
 pub(crate) async fn create_user(
     #[body] new_user: Json<NewUser>,
-    #[inject] db_pool: Arc<dyn DbPoolGetter>,
+    #[inject] users: Arc<dyn UserRepository>,
     #[middleware::request(0)] _: String,
 ) -> Result<Json<User>, UserError> {
-    let conn = db_pool.pool().get()?;
-
-    //diesel does not have an async api
-    //we don't want to block the server thread
-    //so we will offload this as a blocking task
-    // to be executed on an appropriate thread
-    // and we will wait for the result on an async channel
-    let job = move || models::create_user(new_user.into_inner(), &conn);
-    let user = darpi::oneshot(IOBlockingJob::from(job))
-        .await
-        .map_err(|_| UserError::InternalError)?
-        .await
-        .map_err(|_| UserError::InternalError)??;
+    let new_user = new_user.into_inner().validate()?;
+    let user = users.create(new_user).await?;
 
     Ok(Json(user))
 }
@@ -88,27 +158,84 @@ pub struct UserID {
 
 // here we give the container type
 // so the framework knows where to get
-// the requested `Arc<dyn DbPoolGetter>` from
+// the requested `Arc<dyn UserRepository>` from
+// like the list, only for authenticated users
 #[handler({
-    container: Container
+    container: Container,
+    middleware: {
+        request: [authorize(Role::User)]
+    }
 })]
 pub(crate) async fn get_user(
     #[path] user_id: UserID,
-    #[inject] db_pool: Arc<dyn DbPoolGetter>,
-) -> Result<Option<Json<User>>, UserError> {
-    let conn = db_pool.pool().get()?;
-
-    //diesel does not have an async api
-    //we don't want to block the server thread
-    //so we will offload this as a blocking task
-    // to be executed on an appropriate thread
-    // and we will wait for the result on an async channel
-    let job = move || models::find_user_by_id(user_id.id, &conn);
-    let user = darpi::oneshot(IOBlockingJob::from(job))
-        .await
-        .map_err(|_| UserError::InternalError)?
-        .await
-        .map_err(|_| UserError::InternalError)??;
-
-    user.map_or(Ok(None), |u| Ok(Some(Json(u))))
+    #[inject] users: Arc<dyn UserRepository>,
+) -> Result<Json<User>, UserError> {
+    let user = users.find_by_id(user_id.id).await?;
+
+    user.map(Json).ok_or(UserError::NotFound)
+}
+
+const DEFAULT_PAGE_SIZE: i64 = 20;
+const MAX_PAGE_SIZE: i64 = 100;
+
+#[derive(Deserialize, Query)]
+pub struct Pagination {
+    limit: Option<i64>,
+    offset: Option<i64>,
+}
+
+// any authenticated user can browse the users
+#[handler({
+    container: Container,
+    middleware: {
+        request: [authorize(Role::User)]
+    }
+})]
+pub(crate) async fn list_users(
+    #[query] page: Pagination,
+    #[inject] users: Arc<dyn UserRepository>,
+) -> Result<Json<Vec<User>>, UserError> {
+    let limit = page
+        .limit
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .max(1)
+        .min(MAX_PAGE_SIZE);
+    let offset = page.offset.unwrap_or(0).max(0);
+
+    Ok(Json(users.list(limit, offset).await?))
+}
+
+// serves both PUT and PATCH
+// only the fields present in the body are changed
+#[handler({
+    container: Container,
+    middleware: {
+        request: [authorize(Role::Admin)]
+    }
+})]
+pub(crate) async fn update_user(
+    #[path] user_id: UserID,
+    #[body] update: Json<UserUpdate>,
+    #[inject] users: Arc<dyn UserRepository>,
+) -> Result<Json<User>, UserError> {
+    let update = update.into_inner().validate()?;
+    let user = users.update(user_id.id, update).await?;
+
+    user.map(Json).ok_or(UserError::NotFound)
+}
+
+// responds with the deleted user
+#[handler({
+    container: Container,
+    middleware: {
+        request: [authorize(Role::Admin)]
+    }
+})]
+pub(crate) async fn delete_user(
+    #[path] user_id: UserID,
+    #[inject] users: Arc<dyn UserRepository>,
+) -> Result<Json<User>, UserError> {
+    let user = users.delete(user_id.id).await?;
+
+    user.map(Json).ok_or(UserError::NotFound)
 }
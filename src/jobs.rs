@@ -1,5 +1,11 @@
+use crate::repository::RefreshTokenRepository;
+use crate::starwars::StarWars;
 use darpi::job::{CpuJob, FutureJob, IOBlockingJob};
-use darpi::{job_factory, Body, Response};
+use darpi::{job_factory, tokio, Body, Response};
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 //FutureJob types are queued on the regular tokio runtime
 // they are executed in the background and do not hold up the
@@ -52,3 +58,48 @@ async fn first_sync_io_job() -> IOBlockingJob {
     }
     .into()
 }
+
+// not every background job belongs to a request
+// this one is spawned once from main and wakes up on an interval
+// the repository offloads the purge itself like any IOBlockingJob
+pub async fn purge_expired_refresh_tokens(
+    refresh_tokens: Arc<dyn RefreshTokenRepository>,
+    every: Duration,
+) {
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+
+        match refresh_tokens.purge_expired().await {
+            Ok(count) => info!("purged {} expired refresh tokens", count),
+            Err(e) => warn!("could not purge refresh tokens: {}", e),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// swaps in the characters of the data file whenever it changes
+// a file that does not load is logged and the current characters stay
+pub async fn reload_starwars(starwars: StarWars, path: PathBuf, every: Duration) {
+    let mut loaded = modified(&path);
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+
+        let current = modified(&path);
+        if current.is_none() || current == loaded {
+            continue;
+        }
+        loaded = current;
+
+        let (starwars, path) = (starwars.clone(), path.clone());
+        match tokio::task::spawn_blocking(move || starwars.reload(&path)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("keeping the current starwars data, {}", e),
+            Err(e) => warn!("could not reload the starwars data: {}", e),
+        }
+    }
+}
@@ -0,0 +1,340 @@
+use crate::config::{JwtConfig, JwtKeyConfig};
+use crate::middleware::Role;
+use async_graphql::{Error, ErrorExtensions};
+use darpi::chrono::{Duration, Utc};
+use darpi::response::ResponderError;
+use darpi::StatusCode;
+use derive_more::Display;
+use jsonwebtoken::errors::Error as JwtError;
+use jsonwebtoken::{decode, decode_header, encode, Algorithm, DecodingKey, EncodingKey};
+use jsonwebtoken::{Header, Validation};
+use rsa::{PublicKeyParts, RSAPublicKey};
+use serde::{Deserialize, Serialize};
+use shaku::{Component, Interface};
+use std::collections::HashMap;
+use std::path::Path;
+use std::str::FromStr;
+
+// DER prefix of a P-256 SubjectPublicKeyInfo
+// everything after it is the uncompressed point 0x04 || x || y
+const P256_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00, 0x04,
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub role: String,
+    pub exp: i64,
+}
+
+impl Claims {
+    pub fn new(sub: &str, role: Role, valid_for: Duration) -> Self {
+        Self {
+            sub: sub.to_string(),
+            role: role.to_string(),
+            exp: (Utc::now() + valid_for).timestamp(),
+        }
+    }
+}
+
+#[derive(Debug, Display)]
+pub enum KeyError {
+    #[display(fmt = "could not read {}: {}", _0, _1)]
+    Io(String, std::io::Error),
+    #[display(fmt = "invalid key {}: {}", _0, _1)]
+    Invalid(String, String),
+}
+
+// one public key as published on /.well-known/jwks.json
+#[derive(Debug, Clone, Serialize)]
+pub struct Jwk {
+    kty: &'static str,
+    kid: String,
+    alg: String,
+    #[serde(rename = "use")]
+    usage: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    e: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    crv: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    x: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    y: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct JwkSet {
+    pub keys: Vec<Jwk>,
+}
+
+struct VerifyingKey {
+    algorithm: Algorithm,
+    key: DecodingKey<'static>,
+}
+
+// the key we sign with and every key we still accept, by `kid`
+pub struct KeySet {
+    kid: String,
+    algorithm: Algorithm,
+    signing: EncodingKey,
+    verifying: HashMap<String, VerifyingKey>,
+    jwks: JwkSet,
+}
+
+impl KeySet {
+    pub fn from_config(config: &JwtConfig) -> Result<Self, KeyError> {
+        let algorithm = config.algorithm;
+        let kid = config.key_id.clone();
+
+        match &config.keys {
+            JwtKeyConfig::Secret(secret) => {
+                let mut verifying = HashMap::new();
+                verifying.insert(
+                    kid.clone(),
+                    VerifyingKey {
+                        algorithm,
+                        key: DecodingKey::from_secret(secret.as_bytes()).into_static(),
+                    },
+                );
+
+                // a shared secret is never published
+                Ok(Self {
+                    kid,
+                    algorithm,
+                    signing: EncodingKey::from_secret(secret.as_bytes()),
+                    verifying,
+                    jwks: JwkSet::default(),
+                })
+            }
+            JwtKeyConfig::Pem {
+                private_key,
+                public_key,
+                previous_keys_dir,
+            } => {
+                let private_pem = read(private_key)?;
+                let signing = match algorithm {
+                    Algorithm::ES256 => EncodingKey::from_ec_pem(&private_pem),
+                    _ => EncodingKey::from_rsa_pem(&private_pem),
+                }
+                .map_err(|e| KeyError::Invalid(private_key.display().to_string(), e.to_string()))?;
+
+                let mut keys = Self {
+                    kid: kid.clone(),
+                    algorithm,
+                    signing,
+                    verifying: HashMap::new(),
+                    jwks: JwkSet::default(),
+                };
+                keys.add_public_key(kid, algorithm, public_key)?;
+
+                if let Some(dir) = previous_keys_dir {
+                    let entries = std::fs::read_dir(dir)
+                        .map_err(|e| KeyError::Io(dir.display().to_string(), e))?;
+                    for entry in entries {
+                        let path = entry
+                            .map_err(|e| KeyError::Io(dir.display().to_string(), e))?
+                            .path();
+                        if path.extension().and_then(|e| e.to_str()) != Some("pem") {
+                            continue;
+                        }
+                        let stem = path
+                            .file_stem()
+                            .and_then(|s| s.to_str())
+                            .unwrap_or_default();
+                        let (kid, algorithm) = previous_key(stem, algorithm);
+                        keys.add_public_key(kid, algorithm, &path)?;
+                    }
+                }
+
+                Ok(keys)
+            }
+        }
+    }
+
+    fn add_public_key(
+        &mut self,
+        kid: String,
+        algorithm: Algorithm,
+        path: &Path,
+    ) -> Result<(), KeyError> {
+        let invalid = |e: String| KeyError::Invalid(path.display().to_string(), e);
+
+        let pem = read(path)?;
+        let der = pem::parse(&pem)
+            .map_err(|e| invalid(e.to_string()))?
+            .contents;
+
+        let (key, jwk) = match algorithm {
+            Algorithm::ES256 => {
+                let point = der
+                    .strip_prefix(P256_SPKI_PREFIX)
+                    .filter(|p| p.len() == 64)
+                    .ok_or_else(|| invalid("expected a P-256 public key".to_string()))?;
+                let key = DecodingKey::from_ec_pem(&pem).map_err(|e| invalid(e.to_string()))?;
+                let jwk = Jwk {
+                    kty: "EC",
+                    crv: Some("P-256"),
+                    x: Some(base64_url(&point[..32])),
+                    y: Some(base64_url(&point[32..])),
+                    ..Jwk::empty(&kid, algorithm)
+                };
+                (key, jwk)
+            }
+            _ => {
+                let public = RSAPublicKey::from_pkcs8(&der).map_err(|e| invalid(e.to_string()))?;
+                let key = DecodingKey::from_rsa_pem(&pem).map_err(|e| invalid(e.to_string()))?;
+                let jwk = Jwk {
+                    kty: "RSA",
+                    n: Some(base64_url(&public.n().to_bytes_be())),
+                    e: Some(base64_url(&public.e().to_bytes_be())),
+                    ..Jwk::empty(&kid, algorithm)
+                };
+                (key, jwk)
+            }
+        };
+
+        self.jwks.keys.push(jwk);
+        self.verifying.insert(
+            kid,
+            VerifyingKey {
+                algorithm,
+                key: key.into_static(),
+            },
+        );
+        Ok(())
+    }
+}
+
+impl Jwk {
+    fn empty(kid: &str, algorithm: Algorithm) -> Self {
+        Self {
+            kty: "",
+            kid: kid.to_string(),
+            alg: format!("{:?}", algorithm),
+            usage: "sig",
+            n: None,
+            e: None,
+            crv: None,
+            x: None,
+            y: None,
+        }
+    }
+}
+
+// a previous key is `<kid>.pem` when it has the current algorithm
+// and `<kid>.<algorithm>.pem` when it has another, like an RS256 key after moving to ES256
+fn previous_key(stem: &str, current: Algorithm) -> (String, Algorithm) {
+    let named = stem.rfind('.').and_then(|dot| {
+        match Algorithm::from_str(&stem[dot + 1..]) {
+            // a shared secret is never a previous key
+            Ok(Algorithm::HS256) | Ok(Algorithm::HS384) | Ok(Algorithm::HS512) | Err(_) => None,
+            Ok(algorithm) => Some((stem[..dot].to_string(), algorithm)),
+        }
+    });
+    named.unwrap_or_else(|| (stem.to_string(), current))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, KeyError> {
+    std::fs::read(path).map_err(|e| KeyError::Io(path.display().to_string(), e))
+}
+
+fn base64_url(bytes: &[u8]) -> String {
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+#[derive(Debug, Display)]
+pub enum AuthError {
+    #[display(fmt = "missing authorization header")]
+    NoAuthHeader,
+    #[display(fmt = "invalid authorization header")]
+    InvalidAuthHeader,
+    #[display(fmt = "unknown signing key `{}`", _0)]
+    UnknownKey(String),
+    #[display(fmt = "invalid token: {}", _0)]
+    InvalidToken(JwtError),
+    #[display(fmt = "could not create a token: {}", _0)]
+    TokenCreation(JwtError),
+    #[display(fmt = "insufficient permissions")]
+    Forbidden,
+}
+
+impl ResponderError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::TokenCreation(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Forbidden => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+impl ErrorExtensions for AuthError {
+    fn extend(&self) -> Error {
+        Error::new(self.to_string()).extend_with(|_, e| match self {
+            Self::TokenCreation(_) => e.set("code", "INTERNAL_ERROR"),
+            _ => e.set("code", "UNAUTHORIZED"),
+        })
+    }
+}
+
+pub trait JwtKeys: Interface {
+    fn sign(&self, claims: &Claims) -> Result<String, AuthError>;
+    fn verify(&self, token: &str) -> Result<Claims, AuthError>;
+    fn jwks(&self) -> &JwkSet;
+    // how long the access and refresh tokens we hand out are valid
+    fn expiry(&self) -> Duration;
+    fn refresh_expiry(&self) -> Duration;
+}
+
+#[derive(Component)]
+#[shaku(interface = JwtKeys)]
+pub struct JwtKeysImpl {
+    #[shaku(default = unimplemented!())]
+    keys: KeySet,
+    #[shaku(default = unimplemented!())]
+    expiry: Duration,
+    #[shaku(default = unimplemented!())]
+    refresh_expiry: Duration,
+}
+
+impl JwtKeys for JwtKeysImpl {
+    fn sign(&self, claims: &Claims) -> Result<String, AuthError> {
+        let mut header = Header::new(self.keys.algorithm);
+        header.kid = Some(self.keys.kid.clone());
+
+        encode(&header, claims, &self.keys.signing).map_err(AuthError::TokenCreation)
+    }
+
+    fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        let header = decode_header(token).map_err(AuthError::InvalidToken)?;
+
+        // tokens from before key ids existed belong to the current key
+        let kid = header.kid.unwrap_or_else(|| self.keys.kid.clone());
+        let key = self
+            .keys
+            .verifying
+            .get(&kid)
+            .ok_or(AuthError::UnknownKey(kid))?;
+
+        let data = decode::<Claims>(token, &key.key, &Validation::new(key.algorithm))
+            .map_err(AuthError::InvalidToken)?;
+        Ok(data.claims)
+    }
+
+    fn jwks(&self) -> &JwkSet {
+        &self.keys.jwks
+    }
+
+    fn expiry(&self) -> Duration {
+        self.expiry
+    }
+
+    fn refresh_expiry(&self) -> Duration {
+        self.refresh_expiry
+    }
+}
@@ -0,0 +1,253 @@
+pub mod config;
+pub mod graphql;
+pub mod handlers;
+pub mod jobs;
+pub mod jwt;
+pub mod memory;
+pub mod middleware;
+pub mod models;
+pub mod pagination;
+pub mod persisted_queries;
+pub mod repository;
+pub mod schema;
+pub mod starwars;
+pub mod validation;
+
+#[macro_use]
+extern crate diesel;
+
+use config::{Config, StorageConfig};
+use darpi::{app, logger::DefaultFormat, App};
+use darpi_graphql::MultipartOptionsProviderImpl;
+use darpi_middleware::compression::decompress;
+use darpi_middleware::{log_request, log_response};
+use diesel::pg::PgConnection;
+use diesel::r2d2::{self, ConnectionManager};
+use handlers::{
+    create_user, delete_user, get_user, home, jwks, list_users, login, logout, refresh_token,
+    update_user,
+};
+use jobs::*;
+use jwt::{JwtKeysImpl, JwtKeysImplParameters, KeySet};
+use memory::MemoryStore;
+use middleware::{limit_body, BodyLimitGetterImpl, BodyLimitGetterImplParameters};
+use persisted_queries::{PersistedQueriesImpl, PersistedQueriesImplParameters, QueryStore};
+use repository::{CharacterRepository, PgCharacterRepository};
+use repository::{
+    PgRefreshTokenRepository, PgUserRepository, RefreshTokenRepository, UserRepository,
+};
+use shaku::module;
+use shaku::*;
+use starwars::*;
+
+pub trait DbPoolGetter: Interface {
+    fn pool(&self) -> &DbPool;
+}
+
+pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
+
+// there is no pool with the memory storage
+// but then nothing asks for one, the postgres repositories are overridden
+#[derive(Component)]
+#[shaku(interface = DbPoolGetter)]
+pub struct DbPoolGetterImpl {
+    #[shaku(default)]
+    db_pool: Option<DbPool>,
+}
+
+impl DbPoolGetter for DbPoolGetterImpl {
+    fn pool(&self) -> &DbPool {
+        self.db_pool
+            .as_ref()
+            .expect("the postgres storage is not configured")
+    }
+}
+
+module! {
+    pub Container {
+        components = [
+            JwtKeysImpl,
+            BodyLimitGetterImpl,
+            SchemaGetterImpl,
+            DbPoolGetterImpl,
+            PgUserRepository,
+            PgRefreshTokenRepository,
+            PgCharacterRepository,
+            PersistedQueriesImpl,
+            MultipartOptionsProviderImpl
+        ],
+        providers = [],
+    }
+}
+
+// where the repositories keep their data
+// the tests bring their own memory storage
+#[derive(Clone)]
+pub enum Storage {
+    Postgres(DbPool),
+    Memory(MemoryStore, StarWars),
+}
+
+impl Storage {
+    pub fn from_config(config: &Config) -> Self {
+        match &config.storage {
+            StorageConfig::Postgres(database) => {
+                let manager = ConnectionManager::<PgConnection>::new(&database.url);
+                let db_pool = r2d2::Pool::builder()
+                    .max_size(database.pool_size)
+                    .connection_timeout(database.connection_timeout)
+                    .build(manager)
+                    .expect("Failed to create pool.");
+                Self::Postgres(db_pool)
+            }
+            StorageConfig::Memory => {
+                let starwars =
+                    StarWars::load(&config.starwars.data_file).unwrap_or_else(|e| panic!("{}", e));
+                Self::Memory(MemoryStore::default(), starwars)
+            }
+        }
+    }
+}
+
+// our shaku container factory
+// here we setup all our dependencies
+// that can be referenced from handlers by the #[inject] attribute
+pub fn make_container_with(config: &Config, storage: Storage) -> Container {
+    let schema = make_schema(&config.graphql);
+    let timeout = config.graphql.timeout;
+    let playground = config.graphql.playground;
+
+    let keys = KeySet::from_config(&config.jwt).expect("Failed to load jwt keys.");
+    let store =
+        QueryStore::from_config(&config.graphql).expect("Failed to load persisted queries.");
+
+    let builder = Container::builder()
+        .with_component_parameters::<JwtKeysImpl>(JwtKeysImplParameters {
+            keys,
+            expiry: config.jwt.expiry,
+            refresh_expiry: config.jwt.refresh_expiry,
+        })
+        .with_component_parameters::<BodyLimitGetterImpl>(BodyLimitGetterImplParameters {
+            body_limit: config.body_limit,
+        })
+        .with_component_parameters::<SchemaGetterImpl>(SchemaGetterImplParameters {
+            schema,
+            timeout,
+            playground,
+        })
+        .with_component_parameters::<PersistedQueriesImpl>(PersistedQueriesImplParameters {
+            store,
+        });
+
+    match storage {
+        Storage::Postgres(db_pool) => builder
+            .with_component_parameters::<DbPoolGetterImpl>(DbPoolGetterImplParameters {
+                db_pool: Some(db_pool),
+            })
+            .build(),
+        Storage::Memory(store, starwars) => builder
+            .with_component_override::<dyn UserRepository>(Box::new(store.clone()))
+            .with_component_override::<dyn RefreshTokenRepository>(Box::new(store))
+            .with_component_override::<dyn CharacterRepository>(Box::new(starwars))
+            .build(),
+    }
+}
+
+// the whole api, the binary runs it and the integration tests
+// run it against their own container
+pub fn make_app(address: String, container: Container) -> impl App {
+    app!({
+        address: address,
+        container: {
+            factory: container,
+            type: Container
+        },
+        // a set of global middleware that will be executed for every handler
+        // the order matters and it's up to the user to apply them in desired order
+        middleware: {
+            request: [log_request(DefaultFormat), limit_body(), decompress()],
+            response: [log_response(DefaultFormat, request(0))]
+        },
+        jobs: {
+            response: [first_sync_job, first_sync_job1, first_sync_io_job]
+        },
+        handlers: [
+            {
+                route: "/",
+                method: GET,
+                handler: home
+            },
+            {
+                route: "/login",
+                method: POST,
+                handler: login
+            },
+            {
+                route: "/token/refresh",
+                method: POST,
+                handler: refresh_token
+            },
+            {
+                route: "/logout",
+                method: POST,
+                handler: logout
+            },
+            {
+                route: "/.well-known/jwks.json",
+                method: GET,
+                handler: jwks
+            },
+            {
+                route: "/user/{id}",
+                method: GET,
+                handler: get_user
+            },
+            {
+                route: "/user/{id}",
+                method: PUT,
+                handler: update_user
+            },
+            {
+                route: "/user/{id}",
+                method: PATCH,
+                handler: update_user
+            },
+            {
+                route: "/user/{id}",
+                method: DELETE,
+                handler: delete_user
+            },
+            {
+                route: "/user",
+                method: POST,
+                handler: create_user
+            },
+            {
+                route: "/users",
+                method: GET,
+                handler: list_users
+            },
+            //graphql
+            {
+                route: "/starwars",
+                method: POST,
+                handler: starwars_post
+            },
+            {
+                route: "/starwars",
+                method: GET,
+                handler: starwars_get
+            },
+            {
+                route: "/starwars/ws",
+                method: GET,
+                handler: starwars_ws
+            },
+            {
+                route: "/starwars/playground",
+                method: GET,
+                handler: starwars_playground
+            }
+        ]
+    })
+}
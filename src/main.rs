@@ -1,153 +1,72 @@
-mod handlers;
-mod jobs;
-mod middleware;
-mod models;
-mod schema;
-mod starwars;
+use darpi::{tokio, App};
+use example_heroku_darpi::config::Config;
+use example_heroku_darpi::jobs::{purge_expired_refresh_tokens, reload_starwars};
+use example_heroku_darpi::repository::RefreshTokenRepository;
+use example_heroku_darpi::starwars;
+use example_heroku_darpi::{make_app, make_container_with, Storage};
+use shaku::HasComponent;
+use std::sync::Arc;
+use std::time::Duration;
 
-#[macro_use]
-extern crate diesel;
+const REFRESH_TOKEN_PURGE_INTERVAL_SECS: u64 = 60 * 60;
+const DEFAULT_SDL_FILE: &str = "schema.graphql";
 
-use async_graphql::{EmptyMutation, EmptySubscription, Schema};
-use darpi::{app, logger::DefaultFormat, tokio, App};
-use darpi_graphql::MultipartOptionsProviderImpl;
-use darpi_middleware::auth::*;
-use darpi_middleware::{body_size_limit, compression::decompress};
-use darpi_middleware::{log_request, log_response};
-use diesel::pg::PgConnection;
-use diesel::r2d2::{self, ConnectionManager};
-use dotenv::dotenv;
-use handlers::{create_user, get_user, home, login};
-use jobs::*;
-use jsonwebtoken::{DecodingKey, EncodingKey};
-use shaku::module;
-use shaku::*;
-use starwars::*;
-
-pub trait DbPoolGetter: Interface {
-    fn pool(&self) -> &DbPool;
-}
-
-type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
-
-#[derive(Component)]
-#[shaku(interface = DbPoolGetter)]
-pub struct DbPoolGetterImpl {
-    #[shaku(default = unimplemented!())]
-    db_pool: DbPool,
-}
-
-impl DbPoolGetter for DbPoolGetterImpl {
-    fn pool(&self) -> &DbPool {
-        &self.db_pool
+// `sdl [file]` writes the schema for client codegen and exits
+// it needs no config, so it runs anywhere the binary does
+fn write_sdl(path: &str) {
+    if let Err(e) = std::fs::write(path, starwars::sdl()) {
+        eprintln!("could not write {}: {}", path, e);
+        std::process::exit(1);
     }
 }
 
-module! {
-    pub Container {
-        components = [
-            JwtAlgorithmProviderImpl,
-            JwtSecretProviderImpl,
-            TokenExtractorImpl,
-            JwtTokenCreatorImpl,
-            SchemaGetterImpl,
-            DbPoolGetterImpl,
-            MultipartOptionsProviderImpl
-        ],
-        providers = [],
+//todo assert middleware and job types to give more sensible errors
+#[tokio::main]
+async fn main() -> Result<(), darpi::Error> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("sdl") => {
+            write_sdl(args.get(1).map_or(DEFAULT_SDL_FILE, String::as_str));
+            return Ok(());
+        }
+        Some(other) => {
+            eprintln!(
+                "unknown command `{}`, expected `sdl [file]` or nothing",
+                other
+            );
+            std::process::exit(2);
+        }
+        None => {}
     }
-}
 
-// our shaku container factory
-// here we setup all our dependencies
-// that can be referenced from handlers by the #[inject] attribute
-pub(crate) fn make_container() -> Container {
-    let schema = Schema::build(QueryRoot, EmptyMutation, EmptySubscription)
-        .data(StarWars::new())
-        .finish();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    // do not do this in a real application
-    // or you will go to the pit of hell
-    let secret = "my secret".as_ref();
+    // RUST_LOG wins over log_level, to debug one module without touching the config
+    let filters = std::env::var("RUST_LOG").unwrap_or_else(|_| config.log_level.clone());
+    env_logger::Builder::new().parse_filters(&filters).init();
 
-    let connspec = std::env::var("DATABASE_URL").expect("DATABASE_URL");
-    let manager = ConnectionManager::<PgConnection>::new(connspec);
-    let db_pool = r2d2::Pool::builder()
-        .build(manager)
-        .expect("Failed to create pool.");
-
-    let module = Container::builder()
-        .with_component_parameters::<JwtSecretProviderImpl>(JwtSecretProviderImplParameters {
-            encoding_key: EncodingKey::from_secret(secret),
-            decoding_key: DecodingKey::from_secret(secret),
-        })
-        .with_component_parameters::<JwtAlgorithmProviderImpl>(JwtAlgorithmProviderImplParameters {
-            algorithm: Algorithm::HS256,
-        })
-        .with_component_parameters::<SchemaGetterImpl>(SchemaGetterImplParameters { schema })
-        .with_component_parameters::<DbPoolGetterImpl>(DbPoolGetterImplParameters { db_pool })
-        .build();
-    module
-}
+    let address = config.address.clone();
+    let storage = Storage::from_config(&config);
+    if let (Storage::Memory(_, starwars), Some(every)) = (&storage, config.starwars.reload) {
+        tokio::spawn(reload_starwars(
+            starwars.clone(),
+            config.starwars.data_file.clone(),
+            every,
+        ));
+    }
+    let container = make_container_with(&config, storage);
 
-//todo assert middleware and job types to give more sensible errors
-#[tokio::main]
-async fn main() -> Result<(), darpi::Error> {
-    dotenv().ok();
-    std::env::set_var("RUST_LOG", "info");
-    env_logger::init();
+    let refresh_tokens: Arc<dyn RefreshTokenRepository> = container.resolve();
+    tokio::spawn(purge_expired_refresh_tokens(
+        refresh_tokens,
+        Duration::from_secs(REFRESH_TOKEN_PURGE_INTERVAL_SECS),
+    ));
 
-    let port = std::env::var("PORT").unwrap();
-    let address = "0.0.0.0:".to_owned() + &port;
-    app!({
-        address: address,
-        container: {
-            factory: make_container(),
-            type: Container
-        },
-        // a set of global middleware that will be executed for every handler
-        // the order matters and it's up to the user to apply them in desired order
-        middleware: {
-            request: [log_request(DefaultFormat), body_size_limit(128), decompress()],
-            response: [log_response(DefaultFormat, request(0))]
-        },
-        jobs: {
-            response: [first_sync_job, first_sync_job1, first_sync_io_job]
-        },
-        handlers: [
-            {
-                route: "/",
-                method: GET,
-                handler: home
-            },
-            {
-                route: "/login",
-                method: POST,
-                handler: login
-            },
-            {
-                route: "/user/{id}",
-                method: GET,
-                handler: get_user
-            },
-            {
-                route: "/user",
-                method: POST,
-                handler: create_user
-            },
-            //graphql
-            {
-                route: "/starwars",
-                method: POST,
-                handler: starwars_post
-            },
-            {
-                route: "/starwars",
-                method: GET,
-                handler: starwars_get
-            }
-        ]
-    })
-    .run()
-    .await
+    make_app(address, container).run().await
 }
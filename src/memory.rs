@@ -0,0 +1,249 @@
+use crate::models::{self, NewUser, RefreshToken, User, UserError, UserUpdate};
+use crate::repository::{offload, RefreshTokenRepository, UserRepository};
+use async_trait::async_trait;
+use chrono::{Duration, Utc};
+use log::warn;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use uuid::Uuid;
+
+#[derive(Default)]
+struct State {
+    next_user_id: i32,
+    users: BTreeMap<i32, User>,
+    next_token_id: i32,
+    refresh_tokens: Vec<RefreshToken>,
+}
+
+impl State {
+    fn email_taken(&self, email: &str, except: Option<i32>) -> bool {
+        self.users
+            .values()
+            .any(|u| u.email == email && Some(u.id) != except)
+    }
+
+    fn create_refresh_token(
+        &mut self,
+        user_id: i32,
+        family: String,
+        valid_for: Duration,
+    ) -> String {
+        let raw = models::generate_token();
+        self.next_token_id += 1;
+        self.refresh_tokens.push(RefreshToken {
+            id: self.next_token_id,
+            user_id,
+            token_hash: models::hash_token(&raw),
+            family,
+            expires_at: Utc::now() + valid_for,
+            revoked_at: None,
+            created_at: Utc::now(),
+        });
+        raw
+    }
+
+    fn revoke_family(&mut self, family: &str) {
+        let now = Utc::now();
+        self.refresh_tokens
+            .iter_mut()
+            .filter(|t| t.family == family && t.revoked_at.is_none())
+            .for_each(|t| t.revoked_at = Some(now));
+    }
+}
+
+// behaves like the postgres repositories, minus durability
+// one store backs both repositories, refresh tokens need the users
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    state: Arc<Mutex<State>>,
+}
+
+impl MemoryStore {
+    // the lock is never held across an await
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("memory store poisoned")
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryStore {
+    async fn create(&self, new_user: NewUser) -> Result<User, UserError> {
+        let password = new_user.password;
+        let password_hash = offload(move || models::hash_password(&password)).await?;
+
+        let mut state = self.state();
+        if state.email_taken(&new_user.email, None) {
+            return Err(UserError::Conflict("email"));
+        }
+
+        state.next_user_id += 1;
+        let user = User {
+            id: state.next_user_id,
+            first_name: new_user.first_name,
+            last_name: new_user.last_name,
+            email: new_user.email,
+            password_hash,
+            role: new_user.role,
+        };
+        state.users.insert(user.id, user.clone());
+
+        Ok(user)
+    }
+
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, UserError> {
+        Ok(self.state().users.get(&id).cloned())
+    }
+
+    async fn find_by_ids(&self, ids: &[i32]) -> Result<Vec<User>, UserError> {
+        let state = self.state();
+        Ok(ids
+            .iter()
+            .filter_map(|id| state.users.get(id).cloned())
+            .collect())
+    }
+
+    async fn authenticate(
+        &self,
+        email: String,
+        password: String,
+    ) -> Result<Option<User>, UserError> {
+        let user = self
+            .state()
+            .users
+            .values()
+            .find(|u| u.email == email)
+            .cloned();
+
+        // an unknown email is verified too, it must not answer any faster
+        offload(move || {
+            let valid = models::verify_password(&password, user.as_ref());
+            Ok(user.filter(|_| valid))
+        })
+        .await
+    }
+
+    async fn list(&self, limit: i64, offset: i64) -> Result<Vec<User>, UserError> {
+        Ok(self
+            .state()
+            .users
+            .values()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn update(&self, id: i32, update: UserUpdate) -> Result<Option<User>, UserError> {
+        let password_hash = match update.password {
+            Some(password) => Some(offload(move || models::hash_password(&password)).await?),
+            None => None,
+        };
+
+        let mut state = self.state();
+        if let Some(email) = &update.email {
+            if state.email_taken(email, Some(id)) {
+                return Err(UserError::Conflict("email"));
+            }
+        }
+
+        let user = match state.users.get_mut(&id) {
+            Some(user) => user,
+            None => return Ok(None),
+        };
+        if let Some(first_name) = update.first_name {
+            user.first_name = first_name;
+        }
+        if let Some(last_name) = update.last_name {
+            user.last_name = last_name;
+        }
+        if let Some(email) = update.email {
+            user.email = email;
+        }
+        if let Some(password_hash) = password_hash {
+            user.password_hash = password_hash;
+        }
+        if let Some(role) = update.role {
+            user.role = role;
+        }
+
+        Ok(Some(user.clone()))
+    }
+
+    async fn delete(&self, id: i32) -> Result<Option<User>, UserError> {
+        let mut state = self.state();
+        let user = state.users.remove(&id);
+        // mirrors ON DELETE CASCADE
+        state.refresh_tokens.retain(|t| t.user_id != id);
+
+        Ok(user)
+    }
+}
+
+#[async_trait]
+impl RefreshTokenRepository for MemoryStore {
+    async fn create(&self, user_id: i32, valid_for: Duration) -> Result<String, UserError> {
+        let family = Uuid::new_v4().to_string();
+        Ok(self
+            .state()
+            .create_refresh_token(user_id, family, valid_for))
+    }
+
+    async fn rotate(&self, raw: String, valid_for: Duration) -> Result<(User, String), UserError> {
+        let hash = models::hash_token(&raw);
+        let mut state = self.state();
+
+        let token = state
+            .refresh_tokens
+            .iter_mut()
+            .find(|t| t.token_hash == hash)
+            .ok_or(UserError::InvalidRefreshToken)?;
+
+        if token.revoked_at.is_some() {
+            let (family, user_id) = (token.family.clone(), token.user_id);
+            state.revoke_family(&family);
+            warn!("refresh token reuse detected for user {}", user_id);
+            return Err(UserError::InvalidRefreshToken);
+        }
+
+        if token.expires_at < Utc::now() {
+            return Err(UserError::InvalidRefreshToken);
+        }
+
+        token.revoked_at = Some(Utc::now());
+        let (family, user_id) = (token.family.clone(), token.user_id);
+
+        let user = state
+            .users
+            .get(&user_id)
+            .cloned()
+            .ok_or(UserError::InvalidRefreshToken)?;
+        let raw = state.create_refresh_token(user_id, family, valid_for);
+
+        Ok((user, raw))
+    }
+
+    async fn revoke(&self, raw: String) -> Result<(), UserError> {
+        let hash = models::hash_token(&raw);
+        let mut state = self.state();
+
+        let family = state
+            .refresh_tokens
+            .iter()
+            .find(|t| t.token_hash == hash)
+            .map(|t| t.family.clone())
+            .ok_or(UserError::InvalidRefreshToken)?;
+        state.revoke_family(&family);
+
+        Ok(())
+    }
+
+    async fn purge_expired(&self) -> Result<usize, UserError> {
+        let now = Utc::now();
+        let mut state = self.state();
+
+        let before = state.refresh_tokens.len();
+        state.refresh_tokens.retain(|t| t.expires_at >= now);
+
+        Ok(before - state.refresh_tokens.len())
+    }
+}
@@ -1,8 +1,21 @@
-use darpi::{middleware, Body, Request};
-use darpi_middleware::auth::{Claims, UserRole};
-use log::info;
+use crate::jwt::{AuthError, Claims, JwtKeys};
+use crate::models::error_response;
+use async_graphql::Enum;
+use darpi::response::ResponderError;
+use darpi::{middleware, Body, Request, Response, StatusCode};
+use derive_more::Display;
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Text;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use shaku::{Component, Interface};
 use std::convert::Infallible;
 use std::fmt;
+use std::io::Write;
+use std::str::FromStr;
+use std::sync::Arc;
 
 #[middleware(Request)]
 pub(crate) async fn roundtrip(
@@ -13,24 +26,180 @@ pub(crate) async fn roundtrip(
     Ok(res)
 }
 
-#[derive(Clone, PartialEq, PartialOrd)]
+pub trait BodyLimitGetter: Interface {
+    // max request body size in bytes
+    fn body_limit(&self) -> u64;
+}
+
+#[derive(Component)]
+#[shaku(interface = BodyLimitGetter)]
+pub struct BodyLimitGetterImpl {
+    #[shaku(default = unimplemented!())]
+    body_limit: u64,
+}
+
+impl BodyLimitGetter for BodyLimitGetterImpl {
+    fn body_limit(&self) -> u64 {
+        self.body_limit
+    }
+}
+
+#[derive(Debug, Display)]
+#[display(fmt = "the body is {} bytes, at most {} are accepted", _0, _1)]
+pub struct PayloadTooLarge(u64, u64);
+
+impl ResponderError for PayloadTooLarge {
+    fn respond_err(&self) -> Response<Body> {
+        error_response(
+            self.status_code(),
+            "payload_too_large",
+            self.to_string(),
+            None,
+            self,
+        )
+    }
+
+    fn status_code(&self) -> StatusCode {
+        StatusCode::PAYLOAD_TOO_LARGE
+    }
+}
+
+// rejects a request by its content-length before the body is read
+// the limit comes from the container, like everything the config sets
+#[middleware(Request)]
+pub(crate) async fn limit_body(
+    #[request] rp: &Request<Body>,
+    #[inject] limit: Arc<dyn BodyLimitGetter>,
+) -> Result<(), PayloadTooLarge> {
+    let length = rp
+        .headers()
+        .get("content-length")
+        .and_then(|length| length.to_str().ok())
+        .and_then(|length| length.parse::<u64>().ok());
+
+    match length {
+        Some(length) if length > limit.body_limit() => {
+            Err(PayloadTooLarge(length, limit.body_limit()))
+        }
+        _ => Ok(()),
+    }
+}
+
+// verifies the bearer token against our key set
+// and hands the claims to the handler
+#[middleware(Request)]
+pub(crate) async fn authorize(
+    #[request] rp: &Request<Body>,
+    #[handler] role: Role,
+    #[inject] keys: Arc<dyn JwtKeys>,
+) -> Result<Claims, AuthError> {
+    let token = bearer_token(rp)?.ok_or(AuthError::NoAuthHeader)?;
+    let claims = keys.verify(token)?;
+    require_role(Some(&claims), role)?;
+
+    Ok(claims)
+}
+
+// the one authorization rule, for the `authorize` middleware and the graphql `RoleGuard`
+pub(crate) fn require_role(claims: Option<&Claims>, role: Role) -> Result<(), AuthError> {
+    let claims = claims.ok_or(AuthError::NoAuthHeader)?;
+    if !role.is_authorized(claims) {
+        return Err(AuthError::Forbidden);
+    }
+
+    Ok(())
+}
+
+// like `authorize`, but a request without a token gets through without claims
+// a token that is there has to be valid though
+#[middleware(Request)]
+pub(crate) async fn authenticate(
+    #[request] rp: &Request<Body>,
+    #[inject] keys: Arc<dyn JwtKeys>,
+) -> Result<Option<Claims>, AuthError> {
+    bearer_token(rp)?
+        .map(|token| keys.verify(token))
+        .transpose()
+}
+
+pub(crate) fn bearer_token(rp: &Request<Body>) -> Result<Option<&str>, AuthError> {
+    let header = match rp.headers().get("authorization") {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+
+    header
+        .to_str()
+        .ok()
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .map(Some)
+        .ok_or(AuthError::InvalidAuthHeader)
+}
+
+// the variant order matters
+// a role is authorized for everything the roles before it are
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Serialize,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+    Enum,
+    Default,
+)]
+#[sql_type = "Text"]
 pub enum Role {
+    #[default]
     User,
     Admin,
 }
 
-impl Role {
-    pub fn from_str(role: &str) -> Role {
+#[derive(Debug, Display)]
+#[display(fmt = "unknown role `{}`", _0)]
+pub struct UnknownRole(String);
+
+impl std::error::Error for UnknownRole {}
+
+impl FromStr for Role {
+    type Err = UnknownRole;
+
+    fn from_str(role: &str) -> Result<Self, Self::Err> {
         match role {
-            "Admin" => Role::Admin,
-            _ => Role::User,
+            "User" => Ok(Role::User),
+            "Admin" => Ok(Role::Admin),
+            _ => Err(UnknownRole(role.to_string())),
         }
     }
 }
 
-impl UserRole for Role {
-    fn is_authorized(&self, claims: &Claims) -> bool {
-        let other = Self::from_str(claims.role());
+impl ToSql<Text, Pg> for Role {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.to_string().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for Role {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        let role = <String as FromSql<Text, Pg>>::from_sql(bytes)?;
+        Ok(role.parse()?)
+    }
+}
+
+impl Role {
+    pub fn is_authorized(&self, claims: &Claims) -> bool {
+        let other = match claims.role.parse::<Role>() {
+            Ok(other) => other,
+            Err(e) => {
+                warn!("rejecting token: {}", e);
+                return false;
+            }
+        };
         info!("required: {} given: {}", self, other);
         &other >= self
     }
@@ -1,29 +1,182 @@
-use crate::schema::users;
+use crate::middleware::Role;
+use crate::schema::{character_episodes, character_friends, characters, heroes};
+use crate::schema::{refresh_tokens, users};
+use crate::starwars::{id_order, next_id, CharacterChanges, CharacterError, CharacterKind};
+use crate::starwars::{CharacterSearch, Episode, StarWarsChar};
+use crate::validation::{self, ValidationError};
+use async_graphql::{Error, ErrorExtensions, InputObject, SimpleObject};
+use bcrypt::BcryptError;
+use chrono::{DateTime, Duration, Utc};
 use darpi::response::ResponderError;
-use darpi::tokio;
+use darpi::{tokio, Body, Response, StatusCode};
 use derive_more::Display;
 use diesel::prelude::*;
 use diesel::query_dsl::filter_dsl::FilterDsl;
-use diesel::result::Error as DieselError;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use diesel::sql_types::{Nullable, Text};
 use diesel::{ExpressionMethods, Insertable, Queryable};
 use diesel::{PgConnection, RunQueryDsl};
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
 use r2d2::Error as R2D2Error;
+use rand::RngCore;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fmt;
+use uuid::Uuid;
 
-#[derive(Debug, Clone, Queryable, Insertable, Deserialize, Serialize)]
+#[derive(Debug, Clone, Queryable, Insertable, Deserialize, Serialize, SimpleObject)]
 pub struct User {
     pub id: i32,
     pub first_name: String,
     pub last_name: String,
     pub email: String,
+    // never leave the server
+    #[serde(skip)]
+    #[graphql(skip)]
+    pub password_hash: String,
+    pub role: Role,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize, Insertable)]
-#[table_name = "users"]
+#[derive(Debug, Clone, Deserialize, Serialize, InputObject)]
 pub struct NewUser {
     pub first_name: String,
     pub last_name: String,
     pub email: String,
+    #[serde(skip_serializing)]
+    pub password: String,
+    #[serde(default)]
+    #[graphql(default)]
+    pub role: Role,
+}
+
+impl NewUser {
+    // returns the user with trimmed names and a normalized email
+    pub fn validate(self) -> Result<Self, ValidationError> {
+        validation::password(&self.password)?;
+
+        Ok(Self {
+            first_name: validation::name("first_name", self.first_name)?,
+            last_name: validation::name("last_name", self.last_name)?,
+            email: validation::email(self.email)?,
+            password: self.password,
+            role: self.role,
+        })
+    }
+}
+
+// the row we actually store
+// the plain text password is replaced by its hash
+#[derive(Insertable)]
+#[table_name = "users"]
+struct InsertableUser {
+    first_name: String,
+    last_name: String,
+    email: String,
+    password_hash: String,
+    role: Role,
+}
+
+// every field is optional
+// so it can back both PUT and PATCH
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct UserUpdate {
+    pub first_name: Option<String>,
+    pub last_name: Option<String>,
+    pub email: Option<String>,
+    #[serde(skip_serializing)]
+    pub password: Option<String>,
+    pub role: Option<Role>,
+}
+
+impl UserUpdate {
+    pub fn validate(self) -> Result<Self, ValidationError> {
+        if let Some(password) = &self.password {
+            validation::password(password)?;
+        }
+
+        Ok(Self {
+            first_name: self
+                .first_name
+                .map(|n| validation::name("first_name", n))
+                .transpose()?,
+            last_name: self
+                .last_name
+                .map(|n| validation::name("last_name", n))
+                .transpose()?,
+            email: self.email.map(validation::email).transpose()?,
+            password: self.password,
+            role: self.role,
+        })
+    }
+}
+
+#[derive(AsChangeset)]
+#[table_name = "users"]
+struct UserChangeset {
+    first_name: Option<String>,
+    last_name: Option<String>,
+    email: Option<String>,
+    password_hash: Option<String>,
+    role: Option<Role>,
+}
+
+impl UserChangeset {
+    // diesel refuses to run an update without any columns to set
+    fn is_empty(&self) -> bool {
+        self.first_name.is_none()
+            && self.last_name.is_none()
+            && self.email.is_none()
+            && self.password_hash.is_none()
+            && self.role.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Queryable)]
+pub struct RefreshToken {
+    pub id: i32,
+    pub user_id: i32,
+    pub token_hash: String,
+    // every token rotated from the same login shares a family
+    pub family: String,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[table_name = "refresh_tokens"]
+struct NewRefreshToken {
+    user_id: i32,
+    token_hash: String,
+    family: String,
+    expires_at: DateTime<Utc>,
+}
+
+// the friends and episodes of a character live in tables of their own
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "characters"]
+pub struct CharacterRow {
+    pub id: String,
+    pub name: String,
+    pub kind: CharacterKind,
+    pub home_planet: Option<String>,
+    pub primary_function: Option<String>,
+}
+
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "character_friends"]
+pub struct CharacterFriend {
+    pub character_id: String,
+    pub friend_id: String,
+}
+
+#[derive(Debug, Clone, Queryable, Insertable)]
+#[table_name = "character_episodes"]
+pub struct CharacterEpisode {
+    pub character_id: String,
+    pub episode: Episode,
 }
 
 #[derive(Display)]
@@ -31,6 +184,16 @@ pub enum UserError {
     DBError(R2D2Error),
     InsertError(DieselError),
     TokioError(tokio::task::JoinError),
+    #[display(fmt = "blocking job failed: {}", _0)]
+    BlockingJob(String),
+    HashError(BcryptError),
+    Validation(ValidationError),
+    // the name of the field that must be unique
+    #[display(fmt = "{} is already taken", _0)]
+    Conflict(&'static str),
+    NotFound,
+    InvalidCredentials,
+    InvalidRefreshToken,
     InternalError,
 }
 
@@ -42,28 +205,201 @@ impl From<R2D2Error> for UserError {
 
 impl From<DieselError> for UserError {
     fn from(e: DieselError) -> Self {
+        if let DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, info) = &e {
+            if let Some(field) = unique_field(info.constraint_name()) {
+                return Self::Conflict(field);
+            }
+        }
         Self::InsertError(e)
     }
 }
 
+// maps the unique indexes from our migrations
+// back to the field they guard
+fn unique_field(constraint: Option<&str>) -> Option<&'static str> {
+    match constraint? {
+        "users_email_idx" => Some("email"),
+        _ => None,
+    }
+}
+
 impl From<tokio::task::JoinError> for UserError {
     fn from(e: tokio::task::JoinError) -> Self {
         Self::TokioError(e)
     }
 }
 
-impl ResponderError for UserError {}
+impl From<BcryptError> for UserError {
+    fn from(e: BcryptError) -> Self {
+        Self::HashError(e)
+    }
+}
+
+impl From<ValidationError> for UserError {
+    fn from(e: ValidationError) -> Self {
+        Self::Validation(e)
+    }
+}
+
+// the only shape of error a client ever sees
+// the details stay in our logs, next to the same request_id
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: String,
+    request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'a str>,
+}
+
+impl UserError {
+    fn code(&self) -> &'static str {
+        match self {
+            Self::DBError(_) => "service_unavailable",
+            Self::Validation(_) => "invalid_input",
+            Self::Conflict(_) => "conflict",
+            Self::NotFound => "not_found",
+            Self::InvalidCredentials => "invalid_credentials",
+            Self::InvalidRefreshToken => "invalid_refresh_token",
+            _ => "internal_error",
+        }
+    }
+
+    // unlike `Display`, safe to show to clients
+    fn public_message(&self) -> String {
+        match self {
+            Self::DBError(_) => "the service is temporarily unavailable".to_string(),
+            Self::Validation(e) => e.message.clone(),
+            Self::Conflict(_) => self.to_string(),
+            Self::NotFound => "user not found".to_string(),
+            Self::InvalidCredentials => "invalid email or password".to_string(),
+            Self::InvalidRefreshToken => "invalid refresh token".to_string(),
+            _ => "internal server error".to_string(),
+        }
+    }
+
+    fn field(&self) -> Option<&'static str> {
+        match self {
+            Self::Validation(e) => Some(e.field),
+            Self::Conflict(field) => Some(field),
+            _ => None,
+        }
+    }
+}
+
+// the same code, message and field as the json error body
+impl ErrorExtensions for UserError {
+    fn extend(&self) -> Error {
+        if self.status_code().is_server_error() {
+            error!("graphql: {}", self);
+        }
+
+        Error::new(self.public_message()).extend_with(|_, e| {
+            e.set("code", self.code().to_uppercase());
+            if let Some(field) = self.field() {
+                e.set("field", field);
+            }
+        })
+    }
+}
+
+// logs `error` and answers with an `ErrorBody`, for every error type a handler returns
+pub(crate) fn error_response(
+    status: StatusCode,
+    code: &str,
+    message: String,
+    field: Option<&str>,
+    error: &dyn fmt::Display,
+) -> Response<Body> {
+    // there is no request id middleware,
+    // so every error response gets its own id
+    let request_id = Uuid::new_v4().to_string();
+
+    if status.is_server_error() {
+        error!("[{}] {}: {}", request_id, status, error);
+    } else {
+        info!("[{}] {}: {}", request_id, status, error);
+    }
+
+    let body = ErrorBody {
+        code,
+        message,
+        request_id,
+        field,
+    };
+
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(
+            serde_json::to_vec(&body).expect("serializable error"),
+        ))
+        .expect("valid response")
+}
+
+impl ResponderError for UserError {
+    fn respond_err(&self) -> Response<Body> {
+        error_response(
+            self.status_code(),
+            self.code(),
+            self.public_message(),
+            self.field(),
+            self,
+        )
+    }
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::DBError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::InvalidCredentials | Self::InvalidRefreshToken => StatusCode::UNAUTHORIZED,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+// bcrypt is deliberately slow
+// only call these from a blocking context
+pub fn hash_password(password: &str) -> Result<String, UserError> {
+    Ok(bcrypt::hash(password, bcrypt::DEFAULT_COST)?)
+}
+
+// what a password is checked against when there is no hash to check it against,
+// so an unknown email takes as long to reject as a wrong password
+static DUMMY_HASH: Lazy<String> =
+    Lazy::new(|| bcrypt::hash("not anyone's password", bcrypt::DEFAULT_COST).unwrap());
+
+// a hash bcrypt can not read is a mismatch too,
+// users from before passwords were stored have an empty one
+pub fn verify_password(password: &str, user: Option<&User>) -> bool {
+    match user.map(|u| bcrypt::verify(password, &u.password_hash)) {
+        Some(Ok(valid)) => valid,
+        _ => {
+            let _ = bcrypt::verify(password, &*DUMMY_HASH);
+            false
+        }
+    }
+}
 
 pub fn create_user(
     // prevent collision with `name` column imported inside the function
     new_user: NewUser,
     conn: &PgConnection,
-) -> Result<User, DieselError> {
+) -> Result<User, UserError> {
     use crate::schema::users::dsl::*;
 
-    let new_user = diesel::insert_into(users)
-        .values(new_user)
-        .get_result(conn)?;
+    let password = hash_password(&new_user.password)?;
+    let row = InsertableUser {
+        first_name: new_user.first_name,
+        last_name: new_user.last_name,
+        email: new_user.email,
+        password_hash: password,
+        role: new_user.role,
+    };
+
+    let new_user = diesel::insert_into(users).values(row).get_result(conn)?;
 
     Ok(new_user)
 }
@@ -77,3 +413,508 @@ pub fn find_user_by_id(user_id: i32, conn: &PgConnection) -> Result<Option<User>
 
     Ok(user)
 }
+
+pub fn find_users_by_ids(user_ids: &[i32], conn: &PgConnection) -> Result<Vec<User>, DieselError> {
+    use crate::schema::users::dsl::*;
+
+    FilterDsl::filter(users, id.eq_any(user_ids)).load::<User>(conn)
+}
+
+pub fn find_user_by_email(
+    user_email: &str,
+    conn: &PgConnection,
+) -> Result<Option<User>, DieselError> {
+    use crate::schema::users::dsl::*;
+
+    let user = FilterDsl::filter(users, email.eq(user_email))
+        .first::<User>(conn)
+        .optional()?;
+
+    Ok(user)
+}
+
+pub fn list_users(limit: i64, offset: i64, conn: &PgConnection) -> Result<Vec<User>, DieselError> {
+    use crate::schema::users::dsl::*;
+
+    let page = users
+        .order(id.asc())
+        .limit(limit)
+        .offset(offset)
+        .load::<User>(conn)?;
+
+    Ok(page)
+}
+
+pub fn update_user(
+    user_id: i32,
+    update: UserUpdate,
+    conn: &PgConnection,
+) -> Result<Option<User>, UserError> {
+    use crate::schema::users::dsl::*;
+
+    let changes = UserChangeset {
+        first_name: update.first_name,
+        last_name: update.last_name,
+        email: update.email,
+        password_hash: update.password.as_deref().map(hash_password).transpose()?,
+        role: update.role,
+    };
+
+    if changes.is_empty() {
+        return Ok(find_user_by_id(user_id, conn)?);
+    }
+
+    let user = diesel::update(users.find(user_id))
+        .set(changes)
+        .get_result::<User>(conn)
+        .optional()?;
+
+    Ok(user)
+}
+
+pub fn delete_user(user_id: i32, conn: &PgConnection) -> Result<Option<User>, DieselError> {
+    use crate::schema::users::dsl::*;
+
+    let user = diesel::delete(users.find(user_id))
+        .get_result::<User>(conn)
+        .optional()?;
+
+    Ok(user)
+}
+
+// refresh tokens are random, so a fast hash is enough
+// we only keep the hash, a database leak does not leak sessions
+pub(crate) fn hash_token(raw: &str) -> String {
+    format!("{:x}", Sha256::digest(raw.as_bytes()))
+}
+
+pub(crate) fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    base64::encode_config(&bytes, base64::URL_SAFE_NO_PAD)
+}
+
+// returns the raw token, it is never stored
+// a new login starts a new family
+pub fn create_refresh_token(
+    owner: i32,
+    token_family: Option<String>,
+    valid_for: Duration,
+    conn: &PgConnection,
+) -> Result<String, DieselError> {
+    use crate::schema::refresh_tokens::dsl::*;
+
+    let raw = generate_token();
+    let row = NewRefreshToken {
+        user_id: owner,
+        token_hash: hash_token(&raw),
+        family: token_family.unwrap_or_else(|| Uuid::new_v4().to_string()),
+        expires_at: Utc::now() + valid_for,
+    };
+
+    diesel::insert_into(refresh_tokens)
+        .values(row)
+        .execute(conn)?;
+
+    Ok(raw)
+}
+
+enum Rotation {
+    Rotated(User, String),
+    Reused(i32),
+}
+
+// exchanges a refresh token for a new one of the same family
+// presenting an already rotated token means it was stolen,
+// so the whole family is revoked
+pub fn rotate_refresh_token(
+    raw: &str,
+    valid_for: Duration,
+    conn: &PgConnection,
+) -> Result<(User, String), UserError> {
+    use crate::schema::refresh_tokens::dsl::*;
+
+    // the revocation on reuse must be committed,
+    // so the transaction succeeds and we fail after it
+    let rotation = conn.transaction::<_, UserError, _>(|| {
+        let token = FilterDsl::filter(refresh_tokens, token_hash.eq(hash_token(raw)))
+            .for_update()
+            .first::<RefreshToken>(conn)
+            .optional()?
+            .ok_or(UserError::InvalidRefreshToken)?;
+
+        if token.revoked_at.is_some() {
+            revoke_family(&token.family, conn)?;
+            return Ok(Rotation::Reused(token.user_id));
+        }
+
+        if token.expires_at < Utc::now() {
+            return Err(UserError::InvalidRefreshToken);
+        }
+
+        diesel::update(refresh_tokens.find(token.id))
+            .set(revoked_at.eq(Utc::now()))
+            .execute(conn)?;
+
+        let user = find_user_by_id(token.user_id, conn)?.ok_or(UserError::InvalidRefreshToken)?;
+        let raw = create_refresh_token(user.id, Some(token.family), valid_for, conn)?;
+
+        Ok(Rotation::Rotated(user, raw))
+    })?;
+
+    match rotation {
+        Rotation::Rotated(user, raw) => Ok((user, raw)),
+        Rotation::Reused(owner) => {
+            warn!("refresh token reuse detected for user {}", owner);
+            Err(UserError::InvalidRefreshToken)
+        }
+    }
+}
+
+// logs out the session the token belongs to
+pub fn revoke_refresh_token(raw: &str, conn: &PgConnection) -> Result<(), UserError> {
+    use crate::schema::refresh_tokens::dsl::*;
+
+    let token = FilterDsl::filter(refresh_tokens, token_hash.eq(hash_token(raw)))
+        .first::<RefreshToken>(conn)
+        .optional()?
+        .ok_or(UserError::InvalidRefreshToken)?;
+
+    revoke_family(&token.family, conn)?;
+
+    Ok(())
+}
+
+fn revoke_family(token_family: &str, conn: &PgConnection) -> Result<usize, DieselError> {
+    use crate::schema::refresh_tokens::dsl::*;
+
+    diesel::update(FilterDsl::filter(
+        refresh_tokens,
+        family.eq(token_family).and(revoked_at.is_null()),
+    ))
+    .set(revoked_at.eq(Utc::now()))
+    .execute(conn)
+}
+
+pub fn purge_expired_refresh_tokens(conn: &PgConnection) -> Result<usize, DieselError> {
+    use crate::schema::refresh_tokens::dsl::*;
+
+    diesel::delete(FilterDsl::filter(refresh_tokens, expires_at.lt(Utc::now()))).execute(conn)
+}
+
+pub fn find_characters_by_ids(
+    ids: &[String],
+    conn: &PgConnection,
+) -> Result<Vec<StarWarsChar>, DieselError> {
+    let rows = FilterDsl::filter(characters::table, characters::id.eq_any(ids))
+        .load::<CharacterRow>(conn)?;
+    let friendships = FilterDsl::filter(
+        character_friends::table,
+        character_friends::character_id.eq_any(ids),
+    )
+    .load::<CharacterFriend>(conn)?;
+    let appearances = FilterDsl::filter(
+        character_episodes::table,
+        character_episodes::character_id.eq_any(ids),
+    )
+    .load::<CharacterEpisode>(conn)?;
+
+    let mut friends = HashMap::<_, Vec<_>>::new();
+    for friendship in friendships {
+        friends
+            .entry(friendship.character_id)
+            .or_default()
+            .push(friendship.friend_id);
+    }
+    let mut episodes = HashMap::<_, Vec<_>>::new();
+    for appearance in appearances {
+        episodes
+            .entry(appearance.character_id)
+            .or_default()
+            .push(appearance.episode);
+    }
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let mut friends = friends.remove(&row.id).unwrap_or_default();
+            friends.sort_by(|a, b| id_order(a).cmp(&id_order(b)));
+            let mut appears_in = episodes.remove(&row.id).unwrap_or_default();
+            appears_in.sort();
+
+            StarWarsChar {
+                id: row.id,
+                name: row.name,
+                kind: row.kind,
+                friends,
+                appears_in,
+                home_planet: row.home_planet,
+                primary_function: row.primary_function,
+            }
+        })
+        .collect())
+}
+
+sql_function!(fn lower(x: Nullable<Text>) -> Nullable<Text>);
+
+// so `%`, `_` and `\` in what clients search for match themselves
+fn escape_like(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+pub fn search_characters(
+    search: &CharacterSearch,
+    conn: &PgConnection,
+) -> Result<Vec<StarWarsChar>, DieselError> {
+    let mut query = characters::table.select(characters::id).into_boxed();
+    if let Some(text) = &search.text {
+        query = FilterDsl::filter(
+            query,
+            characters::name.ilike(format!("%{}%", escape_like(text))),
+        );
+    }
+    if let Some(character_kind) = search.kind {
+        query = FilterDsl::filter(query, characters::kind.eq(character_kind));
+    }
+    if let Some(planet) = &search.home_planet {
+        query = FilterDsl::filter(
+            query,
+            lower(characters::home_planet).eq(planet.to_lowercase()),
+        );
+    }
+    if let Some(episode) = search.episode {
+        let appearances = FilterDsl::filter(
+            character_episodes::table,
+            character_episodes::episode.eq(episode),
+        )
+        .select(character_episodes::character_id);
+        query = FilterDsl::filter(query, characters::id.eq_any(appearances));
+    }
+
+    let ids = query.load::<String>(conn)?;
+    find_characters_by_ids(&ids, conn)
+}
+
+// sorted by `id_order`, like the memory storage
+pub fn character_ids(
+    character_kind: CharacterKind,
+    conn: &PgConnection,
+) -> Result<Vec<String>, DieselError> {
+    let mut ids = FilterDsl::filter(characters::table, characters::kind.eq(character_kind))
+        .select(characters::id)
+        .load::<String>(conn)?;
+    ids.sort_by(|a, b| id_order(a).cmp(&id_order(b)));
+
+    Ok(ids)
+}
+
+// not found is the first of `ids` that does not exist
+fn ensure_characters_exist(ids: &[String], conn: &PgConnection) -> Result<(), CharacterError> {
+    let found = FilterDsl::filter(characters::table, characters::id.eq_any(ids))
+        .select(characters::id)
+        .load::<String>(conn)?;
+
+    match ids.iter().find(|id| !found.iter().any(|f| f == *id)) {
+        Some(missing) => Err(CharacterError::NotFound(missing.clone())),
+        None => Ok(()),
+    }
+}
+
+fn insert_episodes(
+    character_id: &str,
+    episodes: &[Episode],
+    conn: &PgConnection,
+) -> Result<(), DieselError> {
+    let rows: Vec<_> = episodes
+        .iter()
+        .map(|&episode| CharacterEpisode {
+            character_id: character_id.to_string(),
+            episode,
+        })
+        .collect();
+
+    // an insert without any rows is not valid sql
+    if !rows.is_empty() {
+        diesel::insert_into(character_episodes::table)
+            .values(&rows)
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+// both sides of every friendship, so each character finds its friends by its own id
+fn friendship_rows(character_id: &str, friend_ids: &[String]) -> Vec<CharacterFriend> {
+    friend_ids
+        .iter()
+        .flat_map(|friend_id| {
+            vec![
+                CharacterFriend {
+                    character_id: character_id.to_string(),
+                    friend_id: friend_id.clone(),
+                },
+                CharacterFriend {
+                    character_id: friend_id.clone(),
+                    friend_id: character_id.to_string(),
+                },
+            ]
+        })
+        .collect()
+}
+
+// the id is generated, whatever the given character has is ignored
+// returns the new id
+pub fn create_character(
+    character: StarWarsChar,
+    friend_ids: &[String],
+    conn: &PgConnection,
+) -> Result<String, CharacterError> {
+    // the table lock keeps two creates from picking the same id
+    conn.transaction::<_, CharacterError, _>(|| {
+        diesel::sql_query("LOCK TABLE characters IN SHARE ROW EXCLUSIVE MODE").execute(conn)?;
+
+        ensure_characters_exist(friend_ids, conn)?;
+
+        let existing = characters::table
+            .select((characters::id, characters::kind))
+            .load::<(String, CharacterKind)>(conn)?;
+        let character_id = next_id(character.kind, &existing);
+
+        diesel::insert_into(characters::table)
+            .values(CharacterRow {
+                id: character_id.clone(),
+                name: character.name,
+                kind: character.kind,
+                home_planet: character.home_planet,
+                primary_function: character.primary_function,
+            })
+            .execute(conn)?;
+        insert_episodes(&character_id, &character.appears_in, conn)?;
+
+        let friendships = friendship_rows(&character_id, friend_ids);
+        if !friendships.is_empty() {
+            diesel::insert_into(character_friends::table)
+                .values(&friendships)
+                .execute(conn)?;
+        }
+
+        Ok(character_id)
+    })
+}
+
+pub fn update_character(
+    character_id: &str,
+    changes: CharacterChanges,
+    conn: &PgConnection,
+) -> Result<(), CharacterError> {
+    conn.transaction::<_, CharacterError, _>(|| {
+        ensure_characters_exist(&[character_id.to_string()], conn)?;
+
+        if let Some(name) = changes.name {
+            diesel::update(characters::table.find(character_id))
+                .set(characters::name.eq(name))
+                .execute(conn)?;
+        }
+        if let Some(home_planet) = changes.home_planet {
+            diesel::update(characters::table.find(character_id))
+                .set(characters::home_planet.eq(home_planet))
+                .execute(conn)?;
+        }
+        if let Some(primary_function) = changes.primary_function {
+            diesel::update(characters::table.find(character_id))
+                .set(characters::primary_function.eq(primary_function))
+                .execute(conn)?;
+        }
+        if let Some(appears_in) = changes.appears_in {
+            diesel::delete(FilterDsl::filter(
+                character_episodes::table,
+                character_episodes::character_id.eq(character_id),
+            ))
+            .execute(conn)?;
+            insert_episodes(character_id, &appears_in, conn)?;
+        }
+
+        Ok(())
+    })
+}
+
+// false when they were friends already
+pub fn add_character_friend(
+    character_id: &str,
+    friend_id: &str,
+    conn: &PgConnection,
+) -> Result<bool, CharacterError> {
+    conn.transaction::<_, CharacterError, _>(|| {
+        ensure_characters_exist(&[character_id.to_string(), friend_id.to_string()], conn)?;
+
+        let added = diesel::insert_into(character_friends::table)
+            .values(&friendship_rows(character_id, &[friend_id.to_string()]))
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        Ok(added > 0)
+    })
+}
+
+pub fn remove_character_friend(
+    character_id: &str,
+    friend_id: &str,
+    conn: &PgConnection,
+) -> Result<(), CharacterError> {
+    conn.transaction::<_, CharacterError, _>(|| {
+        ensure_characters_exist(&[character_id.to_string(), friend_id.to_string()], conn)?;
+
+        diesel::delete(FilterDsl::filter(
+            character_friends::table,
+            character_friends::character_id
+                .eq(character_id)
+                .and(character_friends::friend_id.eq(friend_id))
+                .or(character_friends::character_id
+                    .eq(friend_id)
+                    .and(character_friends::friend_id.eq(character_id))),
+        ))
+        .execute(conn)?;
+
+        Ok(())
+    })
+}
+
+// the hero of the episode, or of the whole saga when the episode has none of its own
+pub fn find_hero(episode: Option<Episode>, conn: &PgConnection) -> Result<String, DieselError> {
+    let rows = heroes::table
+        .select((heroes::episode, heroes::character_id))
+        .load::<(Option<Episode>, String)>(conn)?;
+    let hero_of = |episode| rows.iter().find(|(e, _)| *e == episode).map(|(_, id)| id);
+
+    episode
+        .and_then(|episode| hero_of(Some(episode)))
+        .or_else(|| hero_of(None))
+        .cloned()
+        .ok_or(DieselError::NotFound)
+}
+
+// the friendships and episodes go with it
+pub fn delete_character(character_id: &str, conn: &PgConnection) -> Result<(), CharacterError> {
+    // the foreign key from heroes refuses it too, this says why
+    let hero = diesel::select(diesel::dsl::exists(FilterDsl::filter(
+        heroes::table,
+        heroes::character_id.eq(character_id),
+    )))
+    .get_result::<bool>(conn)?;
+    if hero {
+        return Err(ValidationError::new("id", "the heroes of the saga can not be deleted").into());
+    }
+
+    let deleted = diesel::delete(characters::table.find(character_id)).execute(conn)?;
+    if deleted == 0 {
+        return Err(CharacterError::NotFound(character_id.to_string()));
+    }
+
+    Ok(())
+}
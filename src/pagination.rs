@@ -0,0 +1,73 @@
+use async_graphql::connection::CursorType;
+use derive_more::Display;
+
+const CURSOR_PREFIX: &str = "cursor:";
+
+#[derive(Debug, Display)]
+#[display(fmt = "invalid cursor")]
+pub struct InvalidCursor;
+
+// what clients see is base64, so they don't come to rely on what is inside
+// inside is the key of the last item they saw, not its position,
+// so a cursor still works after items were added or removed before it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpaqueCursor(pub String);
+
+impl CursorType for OpaqueCursor {
+    type Error = InvalidCursor;
+
+    fn decode_cursor(s: &str) -> Result<Self, Self::Error> {
+        let bytes = base64::decode(s).map_err(|_| InvalidCursor)?;
+        let cursor = String::from_utf8(bytes).map_err(|_| InvalidCursor)?;
+        cursor
+            .strip_prefix(CURSOR_PREFIX)
+            .map(|key| Self(key.to_string()))
+            .ok_or(InvalidCursor)
+    }
+
+    fn encode_cursor(&self) -> String {
+        base64::encode(format!("{}{}", CURSOR_PREFIX, self.0))
+    }
+}
+
+// the items of a page are `keys[start..end]`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Page {
+    pub start: usize,
+    pub end: usize,
+    pub has_previous_page: bool,
+    pub has_next_page: bool,
+}
+
+// the relay algorithm over `keys`, which must be sorted and without duplicates
+// `after` and `before` need not be one of the keys
+// `first` wins over `last` when both are given
+pub fn page<K: Ord>(
+    keys: &[K],
+    after: Option<&K>,
+    before: Option<&K>,
+    first: Option<usize>,
+    last: Option<usize>,
+) -> Page {
+    let mut start = after.map_or(0, |after| match keys.binary_search(after) {
+        Ok(idx) => idx + 1,
+        Err(idx) => idx,
+    });
+    let mut end = before.map_or(keys.len(), |before| match keys.binary_search(before) {
+        Ok(idx) | Err(idx) => idx,
+    });
+    end = end.max(start);
+
+    if let Some(first) = first {
+        end = end.min(start + first);
+    } else if let Some(last) = last {
+        start = start.max(end.saturating_sub(last));
+    }
+
+    Page {
+        start,
+        end,
+        has_previous_page: start > 0,
+        has_next_page: end < keys.len(),
+    }
+}
@@ -0,0 +1,184 @@
+use crate::config::GraphqlConfig;
+use async_graphql::{Error, ErrorExtensions};
+use derive_more::Display;
+use log::info;
+use lru::LruCache;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use shaku::{Component, Interface};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Debug, Display)]
+pub enum PersistedQueryError {
+    // the client retries with the full query, apollo clients expect this exact message
+    #[display(fmt = "PersistedQueryNotFound")]
+    NotFound,
+    #[display(fmt = "provided sha does not match query")]
+    HashMismatch,
+    #[display(fmt = "only persisted queries are allowed")]
+    NotAllowed,
+}
+
+impl ErrorExtensions for PersistedQueryError {
+    fn extend(&self) -> Error {
+        Error::new(self.to_string()).extend_with(|_, e| match self {
+            Self::NotFound => e.set("code", "PERSISTED_QUERY_NOT_FOUND"),
+            Self::HashMismatch => e.set("code", "BAD_USER_INPUT"),
+            Self::NotAllowed => e.set("code", "QUERY_NOT_ALLOWED"),
+        })
+    }
+}
+
+pub enum QueryStore {
+    // apollo's automatic persisted queries
+    // any query runs, the ones sent with a hash are remembered for next time
+    Automatic(Mutex<LruCache<String, String>>),
+    // only the documents loaded at startup run, by hash or by their text
+    // each is there under the hash of the file as read and of its trimmed text
+    Allowlist(HashMap<String, String>),
+}
+
+impl QueryStore {
+    pub fn from_config(config: &GraphqlConfig) -> Result<Self, String> {
+        match &config.allowlist_dir {
+            Some(dir) => Self::allowlist(dir),
+            None => Ok(Self::Automatic(Mutex::new(LruCache::new(
+                config.apq_cache_size,
+            )))),
+        }
+    }
+
+    // every `*.graphql` file in the directory is one allowed document
+    fn allowlist(dir: &Path) -> Result<Self, String> {
+        let read_err = |e: std::io::Error| format!("could not read {}: {}", dir.display(), e);
+
+        let mut documents = HashMap::new();
+        let mut files = 0;
+        for entry in std::fs::read_dir(dir).map_err(read_err)? {
+            let path = entry.map_err(read_err)?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("graphql") {
+                continue;
+            }
+            let document = std::fs::read_to_string(&path)
+                .map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+            // codegen hashes the file, trailing newline and all,
+            // a client that sends the text has usually trimmed it
+            let trimmed = document.trim().to_string();
+            documents.insert(hash(&document), trimmed.clone());
+            documents.insert(hash(&trimmed), trimmed);
+            files += 1;
+        }
+
+        info!(
+            "allowing {} graphql documents from {}",
+            files,
+            dir.display()
+        );
+        Ok(Self::Allowlist(documents))
+    }
+}
+
+// clients send the sha256 of the exact query as lowercase hex
+fn hash(query: &str) -> String {
+    format!("{:x}", Sha256::digest(query.as_bytes()))
+}
+
+// `{"persistedQuery": {"version": 1, "sha256Hash": "..."}}` in the request extensions
+fn persisted_hash(extensions: Option<&Value>) -> Option<String> {
+    extensions?
+        .get("persistedQuery")?
+        .get("sha256Hash")?
+        .as_str()
+        .map(str::to_string)
+}
+
+pub trait PersistedQueries: Interface {
+    // fills in the query of a request that only sent the hash
+    // an empty query means the request had none
+    fn resolve(
+        &self,
+        query: &mut String,
+        extensions: Option<&Value>,
+    ) -> Result<(), PersistedQueryError>;
+
+    // the same for a graphql-ws or graphql-transport-ws message
+    // only the messages that start an operation carry a query
+    fn resolve_message(&self, message: Vec<u8>) -> Result<Vec<u8>, PersistedQueryError> {
+        let mut parsed: Value = match serde_json::from_slice(&message) {
+            Ok(parsed) => parsed,
+            Err(_) => return Ok(message),
+        };
+        match parsed.get("type").and_then(Value::as_str) {
+            Some("start") | Some("subscribe") => {}
+            _ => return Ok(message),
+        }
+        let payload = match parsed.get_mut("payload") {
+            Some(payload) => payload,
+            None => return Ok(message),
+        };
+
+        let mut query = payload
+            .get("query")
+            .and_then(Value::as_str)
+            .unwrap_or_default()
+            .to_string();
+        self.resolve(&mut query, payload.get("extensions"))?;
+        payload["query"] = Value::String(query);
+
+        Ok(serde_json::to_vec(&parsed).expect("serializable message"))
+    }
+}
+
+#[derive(Component)]
+#[shaku(interface = PersistedQueries)]
+pub struct PersistedQueriesImpl {
+    #[shaku(default = unimplemented!())]
+    store: QueryStore,
+}
+
+impl PersistedQueriesImpl {
+    fn cache(cache: &Mutex<LruCache<String, String>>) -> MutexGuard<'_, LruCache<String, String>> {
+        cache.lock().expect("persisted query cache poisoned")
+    }
+}
+
+impl PersistedQueries for PersistedQueriesImpl {
+    fn resolve(
+        &self,
+        query: &mut String,
+        extensions: Option<&Value>,
+    ) -> Result<(), PersistedQueryError> {
+        let sent_hash = persisted_hash(extensions);
+        if let Some(sent_hash) = &sent_hash {
+            if !query.is_empty() && hash(query) != *sent_hash {
+                return Err(PersistedQueryError::HashMismatch);
+            }
+        }
+
+        match &self.store {
+            QueryStore::Automatic(cache) => match sent_hash {
+                Some(sent_hash) if query.is_empty() => {
+                    let cached = Self::cache(cache).get(&sent_hash).cloned();
+                    *query = cached.ok_or(PersistedQueryError::NotFound)?;
+                }
+                Some(sent_hash) => {
+                    Self::cache(cache).put(sent_hash, query.clone());
+                }
+                None => {}
+            },
+            QueryStore::Allowlist(documents) => {
+                // a query sent as text is looked up by its trimmed text
+                let key = sent_hash.unwrap_or_else(|| hash(query.trim()));
+                match documents.get(&key) {
+                    Some(document) => *query = document.clone(),
+                    None if query.is_empty() => return Err(PersistedQueryError::NotFound),
+                    None => return Err(PersistedQueryError::NotAllowed),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,279 @@
+use super::{DbPool, DbPoolGetter};
+use crate::models::{self, NewUser, User, UserError, UserUpdate};
+use crate::starwars::{CharacterChanges, CharacterError, CharacterKind, CharacterSearch};
+use crate::starwars::{Episode, StarWarsChar};
+use async_trait::async_trait;
+use chrono::Duration;
+use darpi::job::IOBlockingJob;
+use diesel::PgConnection;
+use shaku::{Component, Interface};
+use std::sync::Arc;
+
+#[async_trait]
+pub trait UserRepository: Interface {
+    async fn create(&self, new_user: NewUser) -> Result<User, UserError>;
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, UserError>;
+    // the ones that exist, in no particular order
+    async fn find_by_ids(&self, ids: &[i32]) -> Result<Vec<User>, UserError>;
+    // the user with this email, if the password matches
+    async fn authenticate(
+        &self,
+        email: String,
+        password: String,
+    ) -> Result<Option<User>, UserError>;
+    async fn list(&self, limit: i64, offset: i64) -> Result<Vec<User>, UserError>;
+    async fn update(&self, id: i32, update: UserUpdate) -> Result<Option<User>, UserError>;
+    async fn delete(&self, id: i32) -> Result<Option<User>, UserError>;
+}
+
+#[async_trait]
+pub trait RefreshTokenRepository: Interface {
+    // returns the raw token, only its hash is stored
+    async fn create(&self, user_id: i32, valid_for: Duration) -> Result<String, UserError>;
+    async fn rotate(&self, raw: String, valid_for: Duration) -> Result<(User, String), UserError>;
+    async fn revoke(&self, raw: String) -> Result<(), UserError>;
+    async fn purge_expired(&self) -> Result<usize, UserError>;
+}
+
+#[async_trait]
+pub trait CharacterRepository: Interface {
+    // the ones that exist, in no particular order
+    async fn find_by_ids(&self, ids: &[String]) -> Result<Vec<StarWarsChar>, CharacterError>;
+    // in no particular order either, the caller sorts them
+    async fn search(&self, search: CharacterSearch) -> Result<Vec<StarWarsChar>, CharacterError>;
+    // sorted by `id_order`, for the connections
+    async fn ids(&self, kind: CharacterKind) -> Result<Vec<String>, CharacterError>;
+    // the hero of the whole saga without an episode
+    async fn hero(&self, episode: Option<Episode>) -> Result<String, CharacterError>;
+    // the id is generated, whatever the given character has is ignored
+    // the friends must exist, returns the new id
+    async fn create(
+        &self,
+        character: StarWarsChar,
+        friends: Vec<String>,
+    ) -> Result<String, CharacterError>;
+    async fn update(&self, id: String, changes: CharacterChanges) -> Result<(), CharacterError>;
+    // false when they were friends already
+    async fn add_friend(&self, id: String, friend_id: String) -> Result<bool, CharacterError>;
+    async fn remove_friend(&self, id: String, friend_id: String) -> Result<(), CharacterError>;
+    // the heroes can not be deleted
+    async fn delete(&self, id: String) -> Result<(), CharacterError>;
+}
+
+// runs the job on a thread that is ok to block
+// and waits for the result on an async channel
+pub(crate) async fn offload<T, E, F>(job: F) -> Result<T, E>
+where
+    T: Send + 'static,
+    E: From<UserError> + Send + 'static,
+    F: FnOnce() -> Result<T, E> + Send + 'static,
+{
+    darpi::oneshot(IOBlockingJob::from(job))
+        .await
+        .map_err(|e| UserError::BlockingJob(e.to_string()))?
+        .await
+        .map_err(|e| UserError::BlockingJob(e.to_string()))?
+}
+
+//diesel does not have an async api
+//we don't want to block the server thread
+//so we offload the pool checkout and the query as a blocking task
+async fn blocking<T, E, F>(db_pool: &DbPool, job: F) -> Result<T, E>
+where
+    T: Send + 'static,
+    E: From<UserError> + Send + 'static,
+    F: FnOnce(&PgConnection) -> Result<T, E> + Send + 'static,
+{
+    let db_pool = db_pool.clone();
+    offload(move || {
+        let conn = db_pool.get().map_err(UserError::from)?;
+        job(&conn)
+    })
+    .await
+}
+
+#[derive(Component)]
+#[shaku(interface = UserRepository)]
+pub struct PgUserRepository {
+    #[shaku(inject)]
+    db: Arc<dyn DbPoolGetter>,
+}
+
+#[async_trait]
+impl UserRepository for PgUserRepository {
+    async fn create(&self, new_user: NewUser) -> Result<User, UserError> {
+        blocking(self.db.pool(), move |conn| {
+            models::create_user(new_user, conn)
+        })
+        .await
+    }
+
+    async fn find_by_id(&self, id: i32) -> Result<Option<User>, UserError> {
+        blocking(self.db.pool(), move |conn| {
+            Ok(models::find_user_by_id(id, conn)?)
+        })
+        .await
+    }
+
+    async fn find_by_ids(&self, ids: &[i32]) -> Result<Vec<User>, UserError> {
+        let ids = ids.to_vec();
+        blocking(self.db.pool(), move |conn| {
+            Ok(models::find_users_by_ids(&ids, conn)?)
+        })
+        .await
+    }
+
+    async fn authenticate(
+        &self,
+        email: String,
+        password: String,
+    ) -> Result<Option<User>, UserError> {
+        // both the lookup and the password verification are blocking
+        // so they go to the blocking pool together
+        blocking(self.db.pool(), move |conn| {
+            let user = models::find_user_by_email(&email, conn)?;
+            let valid = models::verify_password(&password, user.as_ref());
+            Ok(user.filter(|_| valid))
+        })
+        .await
+    }
+
+    async fn list(&self, limit: i64, offset: i64) -> Result<Vec<User>, UserError> {
+        blocking(self.db.pool(), move |conn| {
+            Ok(models::list_users(limit, offset, conn)?)
+        })
+        .await
+    }
+
+    async fn update(&self, id: i32, update: UserUpdate) -> Result<Option<User>, UserError> {
+        blocking(self.db.pool(), move |conn| {
+            models::update_user(id, update, conn)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: i32) -> Result<Option<User>, UserError> {
+        blocking(self.db.pool(), move |conn| {
+            Ok(models::delete_user(id, conn)?)
+        })
+        .await
+    }
+}
+
+#[derive(Component)]
+#[shaku(interface = RefreshTokenRepository)]
+pub struct PgRefreshTokenRepository {
+    #[shaku(inject)]
+    db: Arc<dyn DbPoolGetter>,
+}
+
+#[async_trait]
+impl RefreshTokenRepository for PgRefreshTokenRepository {
+    async fn create(&self, user_id: i32, valid_for: Duration) -> Result<String, UserError> {
+        blocking(self.db.pool(), move |conn| {
+            Ok(models::create_refresh_token(
+                user_id, None, valid_for, conn,
+            )?)
+        })
+        .await
+    }
+
+    async fn rotate(&self, raw: String, valid_for: Duration) -> Result<(User, String), UserError> {
+        blocking(self.db.pool(), move |conn| {
+            models::rotate_refresh_token(&raw, valid_for, conn)
+        })
+        .await
+    }
+
+    async fn revoke(&self, raw: String) -> Result<(), UserError> {
+        blocking(self.db.pool(), move |conn| {
+            models::revoke_refresh_token(&raw, conn)
+        })
+        .await
+    }
+
+    async fn purge_expired(&self) -> Result<usize, UserError> {
+        blocking(self.db.pool(), |conn| {
+            Ok(models::purge_expired_refresh_tokens(conn)?)
+        })
+        .await
+    }
+}
+
+#[derive(Component)]
+#[shaku(interface = CharacterRepository)]
+pub struct PgCharacterRepository {
+    #[shaku(inject)]
+    db: Arc<dyn DbPoolGetter>,
+}
+
+#[async_trait]
+impl CharacterRepository for PgCharacterRepository {
+    async fn find_by_ids(&self, ids: &[String]) -> Result<Vec<StarWarsChar>, CharacterError> {
+        let ids = ids.to_vec();
+        blocking(self.db.pool(), move |conn| {
+            Ok(models::find_characters_by_ids(&ids, conn)?)
+        })
+        .await
+    }
+
+    async fn search(&self, search: CharacterSearch) -> Result<Vec<StarWarsChar>, CharacterError> {
+        blocking(self.db.pool(), move |conn| {
+            Ok(models::search_characters(&search, conn)?)
+        })
+        .await
+    }
+
+    async fn ids(&self, kind: CharacterKind) -> Result<Vec<String>, CharacterError> {
+        blocking(self.db.pool(), move |conn| {
+            Ok(models::character_ids(kind, conn)?)
+        })
+        .await
+    }
+
+    async fn hero(&self, episode: Option<Episode>) -> Result<String, CharacterError> {
+        blocking(self.db.pool(), move |conn| {
+            Ok(models::find_hero(episode, conn)?)
+        })
+        .await
+    }
+
+    async fn create(
+        &self,
+        character: StarWarsChar,
+        friends: Vec<String>,
+    ) -> Result<String, CharacterError> {
+        blocking(self.db.pool(), move |conn| {
+            models::create_character(character, &friends, conn)
+        })
+        .await
+    }
+
+    async fn update(&self, id: String, changes: CharacterChanges) -> Result<(), CharacterError> {
+        blocking(self.db.pool(), move |conn| {
+            models::update_character(&id, changes, conn)
+        })
+        .await
+    }
+
+    async fn add_friend(&self, id: String, friend_id: String) -> Result<bool, CharacterError> {
+        blocking(self.db.pool(), move |conn| {
+            models::add_character_friend(&id, &friend_id, conn)
+        })
+        .await
+    }
+
+    async fn remove_friend(&self, id: String, friend_id: String) -> Result<(), CharacterError> {
+        blocking(self.db.pool(), move |conn| {
+            models::remove_character_friend(&id, &friend_id, conn)
+        })
+        .await
+    }
+
+    async fn delete(&self, id: String) -> Result<(), CharacterError> {
+        blocking(self.db.pool(), move |conn| {
+            models::delete_character(&id, conn)
+        })
+        .await
+    }
+}
@@ -1,10 +1,69 @@
 use diesel::table;
 
+table! {
+    character_episodes (character_id, episode) {
+        character_id -> Varchar,
+        episode -> Varchar,
+    }
+}
+
+table! {
+    character_friends (character_id, friend_id) {
+        character_id -> Varchar,
+        friend_id -> Varchar,
+    }
+}
+
+table! {
+    characters (id) {
+        id -> Varchar,
+        name -> Varchar,
+        kind -> Varchar,
+        home_planet -> Nullable<Varchar>,
+        primary_function -> Nullable<Varchar>,
+    }
+}
+
+table! {
+    heroes (id) {
+        id -> Int4,
+        episode -> Nullable<Varchar>,
+        character_id -> Varchar,
+    }
+}
+
+table! {
+    refresh_tokens (id) {
+        id -> Int4,
+        user_id -> Int4,
+        token_hash -> Varchar,
+        family -> Varchar,
+        expires_at -> Timestamptz,
+        revoked_at -> Nullable<Timestamptz>,
+        created_at -> Timestamptz,
+    }
+}
+
 table! {
     users (id) {
         id -> Int4,
         first_name -> Varchar,
         last_name -> Varchar,
         email -> Varchar,
+        password_hash -> Varchar,
+        role -> Varchar,
     }
 }
+
+joinable!(character_episodes -> characters (character_id));
+joinable!(heroes -> characters (character_id));
+joinable!(refresh_tokens -> users (user_id));
+
+allow_tables_to_appear_in_same_query!(
+    character_episodes,
+    character_friends,
+    characters,
+    heroes,
+    refresh_tokens,
+    users,
+);
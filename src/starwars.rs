@@ -1,16 +1,84 @@
+use super::config::GraphqlConfig;
 use super::Container;
+use crate::graphql::{data_loader, list_cost, request_data, CachedLoader, RoleGuard};
+use crate::graphql::{UserMutation, UserQuery, MAX_PAGE_SIZE};
+use crate::jwt::{Claims, JwtKeys};
+use crate::middleware::{authenticate, Role as UserRole};
+use crate::models::{error_response, UserError};
+use crate::pagination::{self, OpaqueCursor};
+use crate::persisted_queries::PersistedQueries;
+use crate::repository::{CharacterRepository, UserRepository};
+use crate::validation::{self, ValidationError};
 use async_graphql::connection::{query, Connection, Edge, EmptyFields};
-use async_graphql::{Context, Enum, Interface, Object};
-use async_graphql::{EmptyMutation, EmptySubscription, Schema};
-use darpi::handler;
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::http::{playground_source, GraphQLPlaygroundConfig};
+use async_graphql::http::{WebSocket, WebSocketProtocols, WsMessage};
+use async_graphql::parser::parse_query;
+use async_graphql::parser::types::{DocumentOperations, OperationType};
+use async_graphql::{Context, Enum, Error, ErrorExtensions, InputObject, Interface, Object};
+use async_graphql::{Data, MaybeUndefined, MergedObject, Schema, Subscription, ID};
+use async_trait::async_trait;
+use chrono::NaiveDate;
+use darpi::response::ResponderError;
+use darpi::{handler, Body, StatusCode};
 use darpi_graphql::{GraphQLBody, Request, Response};
+use derive_more::Display;
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::result::Error as DieselError;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Text;
+use futures_util::{future, SinkExt, Stream, StreamExt};
+use log::{info, warn};
+use serde::Deserialize;
 use shaku::{Component, Interface};
-use slab::Slab;
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
+use tokio_tungstenite::tungstenite::protocol::frame::CloseFrame;
+use tokio_tungstenite::tungstenite::protocol::{Message, Role};
+use tokio_tungstenite::WebSocketStream;
+
+// events a subscriber can fall behind by before it misses some
+const EVENT_CAPACITY: usize = 64;
+
+// what a friends list is charged, times the cost of one friend
+const FRIENDS_COST: usize = 5;
+// a page without `first` or `last` is charged as if it were this long
+const UNBOUNDED_PAGE_COST: usize = 100;
+
+// in the order they were released
+const EPISODES: [Episode; 3] = [Episode::NewHope, Episode::Empire, Episode::Jedi];
+
+// where generated ids start for each kind
+const FIRST_HUMAN_ID: u32 = 1000;
+const FIRST_DROID_ID: u32 = 2000;
 
 /// One of the films in the Star Wars Trilogy
-#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+#[derive(
+    Enum,
+    Copy,
+    Clone,
+    Debug,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Deserialize,
+    AsExpression,
+    FromSqlRow,
+)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[sql_type = "Text"]
 pub enum Episode {
     /// Released in 1977.
     NewHope,
@@ -22,73 +90,280 @@ pub enum Episode {
     Jedi,
 }
 
-pub struct Human(usize);
+impl Episode {
+    // the same names as in the data file
+    fn as_str(self) -> &'static str {
+        match self {
+            Episode::NewHope => "NEW_HOPE",
+            Episode::Empire => "EMPIRE",
+            Episode::Jedi => "JEDI",
+        }
+    }
+}
+
+impl ToSql<Text, Pg> for Episode {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for Episode {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match <String as FromSql<Text, Pg>>::from_sql(bytes)?.as_str() {
+            "NEW_HOPE" => Ok(Episode::NewHope),
+            "EMPIRE" => Ok(Episode::Empire),
+            "JEDI" => Ok(Episode::Jedi),
+            other => Err(format!("unknown episode `{}`", other).into()),
+        }
+    }
+}
+
+/// Whether a character is a human or a droid.
+#[derive(Enum, Copy, Clone, Debug, Eq, PartialEq, Deserialize, AsExpression, FromSqlRow)]
+#[serde(rename_all = "lowercase")]
+#[sql_type = "Text"]
+pub enum CharacterKind {
+    Human,
+    Droid,
+}
+
+impl ToSql<Text, Pg> for CharacterKind {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        let kind = match self {
+            CharacterKind::Human => "human",
+            CharacterKind::Droid => "droid",
+        };
+        out.write_all(kind.as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Text, Pg> for CharacterKind {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match <String as FromSql<Text, Pg>>::from_sql(bytes)?.as_str() {
+            "human" => Ok(CharacterKind::Human),
+            "droid" => Ok(CharacterKind::Droid),
+            other => Err(format!("unknown character kind `{}`", other).into()),
+        }
+    }
+}
+
+// batches the characters that one request resolves into a single read of the storage
+pub struct CharactersById {
+    characters: Arc<dyn CharacterRepository>,
+}
+
+#[async_trait]
+impl Loader<String> for CharactersById {
+    type Value = Arc<StarWarsChar>;
+    type Error = Error;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, Self::Value>, Self::Error> {
+        let found = self
+            .characters
+            .find_by_ids(keys)
+            .await
+            .map_err(|e| e.extend())?;
+        Ok(found
+            .into_iter()
+            .map(|character| (character.id.clone(), Arc::new(character)))
+            .collect())
+    }
+}
+
+pub type CharacterLoader = DataLoader<CachedLoader<String, Arc<StarWarsChar>, CharactersById>>;
+
+fn characters<'a>(ctx: &'a Context<'_>) -> &'a Arc<dyn CharacterRepository> {
+    ctx.data_unchecked::<Arc<dyn CharacterRepository>>()
+}
+
+async fn find(ctx: &Context<'_>, id: &str) -> async_graphql::Result<Option<Arc<StarWarsChar>>> {
+    ctx.data_unchecked::<CharacterLoader>()
+        .load_one(id.to_string())
+        .await
+}
+
+// a character that was deleted while the query was resolving it is an error
+async fn load(ctx: &Context<'_>, id: &str) -> async_graphql::Result<Arc<StarWarsChar>> {
+    find(ctx, id)
+        .await?
+        .ok_or_else(|| CharacterError::Deleted.extend())
+}
+
+// all the friends of a character are loaded in one batch
+async fn load_friends(ctx: &Context<'_>, id: &str) -> async_graphql::Result<Vec<Character>> {
+    let character = load(ctx, id).await?;
+    let friends = ctx
+        .data_unchecked::<CharacterLoader>()
+        .load_many(character.friends.iter().cloned())
+        .await?;
+
+    Ok(character
+        .friends
+        .iter()
+        .filter_map(|id| Some(Character::new(id.clone(), friends.get(id)?.kind)))
+        .collect())
+}
+
+async fn load_films(ctx: &Context<'_>, id: &str) -> async_graphql::Result<Vec<Film>> {
+    Ok(load(ctx, id)
+        .await?
+        .appears_in
+        .iter()
+        .copied()
+        .map(Film)
+        .collect())
+}
+
+async fn hero(ctx: &Context<'_>, episode: Option<Episode>) -> async_graphql::Result<Character> {
+    let id = characters(ctx)
+        .hero(episode)
+        .await
+        .map_err(|e| e.extend())?;
+    let kind = load(ctx, &id).await?.kind;
+    Ok(Character::new(id, kind))
+}
+
+// mutated characters must not be served from the cache
+fn forget_loaded(ctx: &Context<'_>) {
+    ctx.data_unchecked::<CharacterLoader>().loader().clear();
+}
+
+pub struct Human(String);
 
 /// A humanoid creature in the Star Wars universe.
 #[Object]
 impl Human {
     /// The id of the human.
-    async fn id(&self, ctx: &Context<'_>) -> &str {
-        ctx.data_unchecked::<StarWars>().chars[self.0].id
+    async fn id(&self) -> String {
+        self.0.clone()
     }
 
     /// The name of the human.
-    async fn name(&self, ctx: &Context<'_>) -> &str {
-        ctx.data_unchecked::<StarWars>().chars[self.0].name
+    async fn name(&self, ctx: &Context<'_>) -> async_graphql::Result<String> {
+        Ok(load(ctx, &self.0).await?.name.clone())
     }
 
     /// The friends of the human, or an empty list if they have none.
-    async fn friends(&self, ctx: &Context<'_>) -> Vec<Character> {
-        ctx.data_unchecked::<StarWars>().chars[self.0]
-            .friends
-            .iter()
-            .map(|id| Human(*id).into())
-            .collect()
+    #[graphql(complexity = "FRIENDS_COST.saturating_mul(child_complexity)")]
+    async fn friends(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Character>> {
+        load_friends(ctx, &self.0).await
     }
 
     /// Which movies they appear in.
-    async fn appears_in<'a>(&self, ctx: &'a Context<'_>) -> &'a [Episode] {
-        &ctx.data_unchecked::<StarWars>().chars[self.0].appears_in
+    async fn appears_in(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Episode>> {
+        Ok(load(ctx, &self.0).await?.appears_in.clone())
+    }
+
+    /// The films they appear in.
+    async fn films(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Film>> {
+        load_films(ctx, &self.0).await
     }
 
     /// The home planet of the human, or null if unknown.
-    async fn home_planet<'a>(&self, ctx: &'a Context<'_>) -> &'a Option<&'a str> {
-        &ctx.data_unchecked::<StarWars>().chars[self.0].home_planet
+    async fn home_planet(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<String>> {
+        Ok(load(ctx, &self.0).await?.home_planet.clone())
     }
 }
 
-pub struct Droid(usize);
+pub struct Droid(String);
 
 /// A mechanical creature in the Star Wars universe.
 #[Object]
 impl Droid {
     /// The id of the droid.
-    async fn id(&self, ctx: &Context<'_>) -> &str {
-        ctx.data_unchecked::<StarWars>().chars[self.0].id
+    async fn id(&self) -> String {
+        self.0.clone()
     }
 
     /// The name of the droid.
-    async fn name(&self, ctx: &Context<'_>) -> &str {
-        ctx.data_unchecked::<StarWars>().chars[self.0].name
+    async fn name(&self, ctx: &Context<'_>) -> async_graphql::Result<String> {
+        Ok(load(ctx, &self.0).await?.name.clone())
     }
 
     /// The friends of the droid, or an empty list if they have none.
-    async fn friends(&self, ctx: &Context<'_>) -> Vec<Character> {
-        ctx.data_unchecked::<StarWars>().chars[self.0]
-            .friends
-            .iter()
-            .map(|id| Droid(*id).into())
-            .collect()
+    #[graphql(complexity = "FRIENDS_COST.saturating_mul(child_complexity)")]
+    async fn friends(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Character>> {
+        load_friends(ctx, &self.0).await
     }
 
     /// Which movies they appear in.
-    async fn appears_in<'a>(&self, ctx: &'a Context<'_>) -> &'a [Episode] {
-        &ctx.data_unchecked::<StarWars>().chars[self.0].appears_in
+    async fn appears_in(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Episode>> {
+        Ok(load(ctx, &self.0).await?.appears_in.clone())
+    }
+
+    /// The films they appear in.
+    async fn films(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Film>> {
+        load_films(ctx, &self.0).await
     }
 
     /// The primary function of the droid.
-    async fn primary_function<'a>(&self, ctx: &'a Context<'_>) -> &'a Option<&'a str> {
-        &ctx.data_unchecked::<StarWars>().chars[self.0].primary_function
+    async fn primary_function(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<String>> {
+        Ok(load(ctx, &self.0).await?.primary_function.clone())
+    }
+}
+
+pub struct Film(Episode);
+
+/// A film of the Star Wars Trilogy.
+#[Object]
+impl Film {
+    async fn episode(&self) -> Episode {
+        self.0
+    }
+
+    async fn title(&self) -> &'static str {
+        match self.0 {
+            Episode::NewHope => "A New Hope",
+            Episode::Empire => "The Empire Strikes Back",
+            Episode::Jedi => "Return of the Jedi",
+        }
+    }
+
+    /// When the film premiered in the United States.
+    async fn release_date(&self) -> NaiveDate {
+        match self.0 {
+            Episode::NewHope => NaiveDate::from_ymd(1977, 5, 25),
+            Episode::Empire => NaiveDate::from_ymd(1980, 5, 21),
+            Episode::Jedi => NaiveDate::from_ymd(1983, 5, 25),
+        }
+    }
+
+    async fn director(&self) -> &'static str {
+        match self.0 {
+            Episode::NewHope => "George Lucas",
+            Episode::Empire => "Irvin Kershner",
+            Episode::Jedi => "Richard Marquand",
+        }
+    }
+
+    /// The hero of the film.
+    async fn hero(&self, ctx: &Context<'_>) -> async_graphql::Result<Character> {
+        hero(ctx, Some(self.0)).await
+    }
+
+    /// The characters that appear in the film, by id.
+    #[graphql(
+        complexity = "list_cost(first.or(last), UNBOUNDED_PAGE_COST).saturating_mul(child_complexity)"
+    )]
+    async fn characters(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<Connection<OpaqueCursor, Character, EmptyFields, EmptyFields>> {
+        let search = CharacterSearch {
+            episode: Some(self.0),
+            ..CharacterSearch::default()
+        };
+        let found = characters(ctx)
+            .search(search)
+            .await
+            .map_err(|e| e.extend())?;
+        search_characters(after, before, first, last, CharacterOrder::IdAsc, found).await
     }
 }
 
@@ -102,23 +377,34 @@ impl QueryRoot {
         #[graphql(
             desc = "If omitted, returns the hero of the whole saga. If provided, returns the hero of that particular episode."
         )]
-        episode: Episode,
-    ) -> Character {
-        if episode == Episode::Empire {
-            Human(ctx.data_unchecked::<StarWars>().luke).into()
-        } else {
-            Droid(ctx.data_unchecked::<StarWars>().artoo).into()
-        }
+        episode: Option<Episode>,
+    ) -> async_graphql::Result<Character> {
+        hero(ctx, episode).await
+    }
+
+    /// The films of the saga, in the order they were released.
+    async fn films(&self) -> Vec<Film> {
+        EPISODES.iter().copied().map(Film).collect()
+    }
+
+    async fn film(&self, episode: Episode) -> Film {
+        Film(episode)
     }
 
     async fn human(
         &self,
         ctx: &Context<'_>,
         #[graphql(desc = "id of the human")] id: String,
-    ) -> Option<Human> {
-        ctx.data_unchecked::<StarWars>().human(&id).map(Human)
+    ) -> async_graphql::Result<Option<Human>> {
+        Ok(find(ctx, &id)
+            .await?
+            .filter(|c| c.kind == CharacterKind::Human)
+            .map(|c| Human(c.id.clone())))
     }
 
+    #[graphql(
+        complexity = "list_cost(first.or(last), UNBOUNDED_PAGE_COST).saturating_mul(child_complexity)"
+    )]
     async fn humans(
         &self,
         ctx: &Context<'_>,
@@ -126,13 +412,11 @@ impl QueryRoot {
         before: Option<String>,
         first: Option<i32>,
         last: Option<i32>,
-    ) -> async_graphql::Result<Connection<usize, Human, EmptyFields, EmptyFields>> {
-        let humans = ctx
-            .data_unchecked::<StarWars>()
-            .humans()
-            .iter()
-            .copied()
-            .collect::<Vec<_>>();
+    ) -> async_graphql::Result<Connection<OpaqueCursor, Human, EmptyFields, EmptyFields>> {
+        let humans = characters(ctx)
+            .ids(CharacterKind::Human)
+            .await
+            .map_err(|e| e.extend())?;
         query_characters(after, before, first, last, &humans)
             .await
             .map(|conn| conn.map_node(Human))
@@ -142,10 +426,16 @@ impl QueryRoot {
         &self,
         ctx: &Context<'_>,
         #[graphql(desc = "id of the droid")] id: String,
-    ) -> Option<Droid> {
-        ctx.data_unchecked::<StarWars>().droid(&id).map(Droid)
+    ) -> async_graphql::Result<Option<Droid>> {
+        Ok(find(ctx, &id)
+            .await?
+            .filter(|c| c.kind == CharacterKind::Droid)
+            .map(|c| Droid(c.id.clone())))
     }
 
+    #[graphql(
+        complexity = "list_cost(first.or(last), UNBOUNDED_PAGE_COST).saturating_mul(child_complexity)"
+    )]
     async fn droids(
         &self,
         ctx: &Context<'_>,
@@ -153,218 +443,1107 @@ impl QueryRoot {
         before: Option<String>,
         first: Option<i32>,
         last: Option<i32>,
-    ) -> async_graphql::Result<Connection<usize, Droid, EmptyFields, EmptyFields>> {
-        let droids = ctx
-            .data_unchecked::<StarWars>()
-            .droids()
-            .iter()
-            .copied()
-            .collect::<Vec<_>>();
+    ) -> async_graphql::Result<Connection<OpaqueCursor, Droid, EmptyFields, EmptyFields>> {
+        let droids = characters(ctx)
+            .ids(CharacterKind::Droid)
+            .await
+            .map_err(|e| e.extend())?;
         query_characters(after, before, first, last, &droids)
             .await
             .map(|conn| conn.map_node(Droid))
     }
+
+    /// Characters matching every given argument, in the order asked for.
+    #[graphql(
+        complexity = "list_cost(first.or(last), UNBOUNDED_PAGE_COST).saturating_mul(child_complexity)"
+    )]
+    #[allow(clippy::too_many_arguments)]
+    async fn search(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(desc = "Part of the name, ignoring case.")] text: Option<String>,
+        #[graphql(desc = "Only those in this episode.")] episode: Option<Episode>,
+        kind: Option<CharacterKind>,
+        #[graphql(desc = "The whole name, ignoring case.")] home_planet: Option<String>,
+        #[graphql(default)] order_by: CharacterOrder,
+        after: Option<String>,
+        before: Option<String>,
+        first: Option<i32>,
+        last: Option<i32>,
+    ) -> async_graphql::Result<Connection<OpaqueCursor, Character, EmptyFields, EmptyFields>> {
+        let search = CharacterSearch {
+            text: non_blank(text),
+            episode,
+            kind,
+            home_planet: non_blank(home_planet),
+        };
+        let found = characters(ctx)
+            .search(search)
+            .await
+            .map_err(|e| e.extend())?;
+        search_characters(after, before, first, last, order_by, found).await
+    }
+}
+
+/// How search results are sorted, ties go by id.
+#[derive(Enum, Copy, Clone, Eq, PartialEq, Default)]
+pub enum CharacterOrder {
+    /// The order of `humans` and `droids`, numeric ids as numbers.
+    #[default]
+    IdAsc,
+    IdDesc,
+    /// Ignoring case.
+    NameAsc,
+    NameDesc,
+}
+
+// what `search` matches, every given part must match
+#[derive(Clone, Default)]
+pub struct CharacterSearch {
+    // anywhere in the name, ignoring case
+    pub text: Option<String>,
+    pub episode: Option<Episode>,
+    pub kind: Option<CharacterKind>,
+    // the whole name of the planet, ignoring case
+    pub home_planet: Option<String>,
+}
+
+impl CharacterSearch {
+    // for the storages that filter in memory
+    pub(crate) fn matches(&self, character: &StarWarsChar) -> bool {
+        let text = self
+            .text
+            .as_ref()
+            .is_none_or(|text| character.name.to_lowercase().contains(&text.to_lowercase()));
+        let planet = self.home_planet.as_ref().is_none_or(|planet| {
+            character
+                .home_planet
+                .as_ref()
+                .is_some_and(|p| p.to_lowercase() == planet.to_lowercase())
+        });
+
+        text && planet
+            && self
+                .episode
+                .is_none_or(|e| character.appears_in.contains(&e))
+            && self.kind.is_none_or(|k| character.kind == k)
+    }
+}
+
+fn non_blank(value: Option<String>) -> Option<String> {
+    value
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
+
+#[derive(InputObject)]
+pub struct NewHuman {
+    name: String,
+    home_planet: Option<String>,
+    #[graphql(default)]
+    appears_in: Vec<Episode>,
+    /// Ids of existing characters, the friendship goes both ways.
+    #[graphql(default)]
+    friends: Vec<ID>,
+}
+
+#[derive(InputObject)]
+pub struct NewDroid {
+    name: String,
+    primary_function: Option<String>,
+    #[graphql(default)]
+    appears_in: Vec<Episode>,
+    /// Ids of existing characters, the friendship goes both ways.
+    #[graphql(default)]
+    friends: Vec<ID>,
+}
+
+/// Only the given fields are changed, null clears an optional field.
+#[derive(InputObject)]
+pub struct CharacterUpdate {
+    name: Option<String>,
+    appears_in: Option<Vec<Episode>>,
+    /// Humans only.
+    home_planet: MaybeUndefined<String>,
+    /// Droids only.
+    primary_function: MaybeUndefined<String>,
+}
+
+// a validated `CharacterUpdate`, `None` leaves a field as it is
+pub struct CharacterChanges {
+    pub name: Option<String>,
+    pub appears_in: Option<Vec<Episode>>,
+    pub home_planet: Option<Option<String>>,
+    pub primary_function: Option<Option<String>>,
+}
+
+impl CharacterChanges {
+    // validated up front so a rejected update changes nothing
+    fn validate(kind: CharacterKind, update: CharacterUpdate) -> Result<Self, CharacterError> {
+        let name = update
+            .name
+            .map(|name| validation::name("name", name))
+            .transpose()?;
+        let appears_in = update.appears_in.map(episodes);
+
+        let home_planet = match update.home_planet {
+            MaybeUndefined::Value(_) if kind == CharacterKind::Droid => {
+                return Err(ValidationError::new("homePlanet", "droids have no home planet").into())
+            }
+            MaybeUndefined::Value(planet) => Some(Some(validation::name("homePlanet", planet)?)),
+            MaybeUndefined::Null => Some(None),
+            MaybeUndefined::Undefined => None,
+        };
+        let primary_function = match update.primary_function {
+            MaybeUndefined::Value(_) if kind == CharacterKind::Human => {
+                return Err(ValidationError::new(
+                    "primaryFunction",
+                    "humans have no primary function",
+                )
+                .into())
+            }
+            MaybeUndefined::Value(function) => {
+                Some(Some(validation::name("primaryFunction", function)?))
+            }
+            MaybeUndefined::Null => Some(None),
+            MaybeUndefined::Undefined => None,
+        };
+
+        Ok(Self {
+            name,
+            appears_in,
+            home_planet,
+            primary_function,
+        })
+    }
+}
+
+// creates the character and tells the subscribers, returns the new id
+async fn create(
+    ctx: &Context<'_>,
+    mut character: StarWarsChar,
+    friends: Vec<ID>,
+) -> Result<String, CharacterError> {
+    character.name = validation::name("name", character.name)?;
+    character.home_planet = optional("homePlanet", character.home_planet)?;
+    character.primary_function = optional("primaryFunction", character.primary_function)?;
+    character.appears_in = episodes(character.appears_in);
+
+    let mut friend_ids: Vec<String> = Vec::with_capacity(friends.len());
+    for friend in friends {
+        if !friend_ids.iter().any(|id| *id == *friend) {
+            friend_ids.push(friend.to_string());
+        }
+    }
+
+    let id = characters(ctx)
+        .create(character, friend_ids.clone())
+        .await?;
+    forget_loaded(ctx);
+
+    let events = ctx.data_unchecked::<CharacterEvents>();
+    events.publish(ChangeKind::Created, &id, None);
+    for friend_id in &friend_ids {
+        events.publish(ChangeKind::Befriended, &id, Some(friend_id));
+    }
+
+    Ok(id)
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    #[graphql(guard(RoleGuard(role = "UserRole::User")))]
+    async fn create_human(
+        &self,
+        ctx: &Context<'_>,
+        input: NewHuman,
+    ) -> async_graphql::Result<Human> {
+        let character = StarWarsChar {
+            id: String::new(),
+            name: input.name,
+            kind: CharacterKind::Human,
+            friends: vec![],
+            appears_in: input.appears_in,
+            home_planet: input.home_planet,
+            primary_function: None,
+        };
+
+        let id = create(ctx, character, input.friends)
+            .await
+            .map_err(|e| e.extend())?;
+        Ok(Human(id))
+    }
+
+    #[graphql(guard(RoleGuard(role = "UserRole::User")))]
+    async fn create_droid(
+        &self,
+        ctx: &Context<'_>,
+        input: NewDroid,
+    ) -> async_graphql::Result<Droid> {
+        let character = StarWarsChar {
+            id: String::new(),
+            name: input.name,
+            kind: CharacterKind::Droid,
+            friends: vec![],
+            appears_in: input.appears_in,
+            home_planet: None,
+            primary_function: input.primary_function,
+        };
+
+        let id = create(ctx, character, input.friends)
+            .await
+            .map_err(|e| e.extend())?;
+        Ok(Droid(id))
+    }
+
+    #[graphql(guard(RoleGuard(role = "UserRole::User")))]
+    async fn update_character(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+        input: CharacterUpdate,
+    ) -> async_graphql::Result<Character> {
+        let id = id.to_string();
+        // what can be changed depends on the kind
+        let kind = find(ctx, &id)
+            .await?
+            .ok_or_else(|| CharacterError::NotFound(id.clone()).extend())?
+            .kind;
+        let changes = CharacterChanges::validate(kind, input).map_err(|e| e.extend())?;
+
+        characters(ctx)
+            .update(id.clone(), changes)
+            .await
+            .map_err(|e| e.extend())?;
+        forget_loaded(ctx);
+        ctx.data_unchecked::<CharacterEvents>()
+            .publish(ChangeKind::Updated, &id, None);
+
+        Ok(Character::new(id, kind))
+    }
+
+    /// Both characters become friends of each other.
+    #[graphql(guard(RoleGuard(role = "UserRole::User")))]
+    async fn add_friend(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+        friend_id: ID,
+    ) -> async_graphql::Result<Character> {
+        let (id, friend_id) = (id.to_string(), friend_id.to_string());
+        if id == friend_id {
+            return Err(CharacterError::from(ValidationError::new(
+                "friends",
+                "a character can not befriend itself",
+            ))
+            .extend());
+        }
+
+        let added = characters(ctx)
+            .add_friend(id.clone(), friend_id.clone())
+            .await
+            .map_err(|e| e.extend())?;
+        forget_loaded(ctx);
+        if added {
+            ctx.data_unchecked::<CharacterEvents>().publish(
+                ChangeKind::Befriended,
+                &id,
+                Some(&friend_id),
+            );
+        }
+
+        let kind = load(ctx, &id).await?.kind;
+        Ok(Character::new(id, kind))
+    }
+
+    /// Both characters stop being friends of each other.
+    #[graphql(guard(RoleGuard(role = "UserRole::User")))]
+    async fn remove_friend(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+        friend_id: ID,
+    ) -> async_graphql::Result<Character> {
+        let id = id.to_string();
+        characters(ctx)
+            .remove_friend(id.clone(), friend_id.to_string())
+            .await
+            .map_err(|e| e.extend())?;
+        forget_loaded(ctx);
+
+        let kind = load(ctx, &id).await?.kind;
+        Ok(Character::new(id, kind))
+    }
+
+    /// Returns the id of the deleted character.
+    #[graphql(guard(RoleGuard(role = "UserRole::Admin")))]
+    async fn delete_character(&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<ID> {
+        characters(ctx)
+            .delete(id.to_string())
+            .await
+            .map_err(|e| e.extend())?;
+        forget_loaded(ctx);
+        Ok(id)
+    }
+}
+
+#[derive(Enum, Copy, Clone, Eq, PartialEq)]
+pub enum ChangeKind {
+    Created,
+    Updated,
+    /// Two characters became friends.
+    Befriended,
+}
+
+#[derive(Clone)]
+pub struct CharacterChanged {
+    kind: ChangeKind,
+    id: String,
+    friend_id: Option<String>,
+}
+
+#[Object]
+impl CharacterChanged {
+    async fn kind(&self) -> ChangeKind {
+        self.kind
+    }
+
+    async fn id(&self) -> ID {
+        ID::from(&self.id)
+    }
+
+    /// The new friend, for `BEFRIENDED` only.
+    async fn friend_id(&self) -> Option<ID> {
+        self.friend_id.as_ref().map(ID::from)
+    }
+
+    /// The character as it is now, null if it was deleted since.
+    async fn character(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Character>> {
+        Ok(find(ctx, &self.id)
+            .await?
+            .map(|c| Character::new(c.id.clone(), c.kind)))
+    }
+}
+
+// what the mutations changed, for the subscriptions
+// part of the schema data, so every storage gets the same events
+pub struct CharacterEvents(broadcast::Sender<CharacterChanged>);
+
+impl CharacterEvents {
+    fn new() -> Self {
+        Self(broadcast::channel(EVENT_CAPACITY).0)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<CharacterChanged> {
+        self.0.subscribe()
+    }
+
+    fn publish(&self, kind: ChangeKind, id: &str, friend_id: Option<&str>) {
+        // fails only when nobody is subscribed
+        let _ = self.0.send(CharacterChanged {
+            kind,
+            id: id.to_string(),
+            friend_id: friend_id.map(str::to_string),
+        });
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// Every change to the characters, or only those of the given kind.
+    /// Like the mutations that make them, it needs a signed in user.
+    #[graphql(guard(RoleGuard(role = "UserRole::User")))]
+    async fn character_changed(
+        &self,
+        ctx: &Context<'_>,
+        kind: Option<ChangeKind>,
+    ) -> impl Stream<Item = CharacterChanged> {
+        // a subscriber that lagged behind skips what it missed
+        BroadcastStream::new(ctx.data_unchecked::<CharacterEvents>().subscribe()).filter_map(
+            move |event| future::ready(event.ok().filter(|e| kind.is_none_or(|k| k == e.kind))),
+        )
+    }
 }
 
 #[derive(Interface)]
 #[graphql(
-    field(name = "id", type = "&str"),
-    field(name = "name", type = "&str"),
+    field(name = "id", type = "String"),
+    field(name = "name", type = "String"),
     field(name = "friends", type = "Vec<Character>"),
-    field(name = "appears_in", type = "&'ctx [Episode]")
+    field(name = "appears_in", type = "Vec<Episode>"),
+    field(name = "films", type = "Vec<Film>")
 )]
 pub enum Character {
     Human(Human),
     Droid(Droid),
 }
 
+impl Character {
+    fn new(id: String, kind: CharacterKind) -> Self {
+        match kind {
+            CharacterKind::Human => Human(id).into(),
+            CharacterKind::Droid => Droid(id).into(),
+        }
+    }
+}
+
+// how characters are ordered in a connection
+// shorter ids first, so numeric ids sort as numbers
+pub(crate) fn id_order(id: &str) -> (usize, &str) {
+    (id.len(), id)
+}
+
+// one past the highest numeric id of that kind,
+// skipping ids that are taken by the other kind
+pub(crate) fn next_id(kind: CharacterKind, existing: &[(String, CharacterKind)]) -> String {
+    let first = match kind {
+        CharacterKind::Human => FIRST_HUMAN_ID,
+        CharacterKind::Droid => FIRST_DROID_ID,
+    };
+
+    let mut next = existing
+        .iter()
+        .filter(|(_, k)| *k == kind)
+        .filter_map(|(id, _)| id.parse::<u32>().ok())
+        .max()
+        .map_or(first, |max| max + 1);
+    while existing.iter().any(|(id, _)| *id == next.to_string()) {
+        next += 1;
+    }
+    next.to_string()
+}
+
+// returns `first` and `last` capped at the longest page, which is what `list_cost` charges
+fn page_size(
+    first: Option<i32>,
+    last: Option<i32>,
+) -> async_graphql::Result<(Option<i32>, Option<i32>)> {
+    for &(field, count) in &[("first", first), ("last", last)] {
+        if count.is_some_and(|count| count < 0) {
+            return Err(
+                CharacterError::from(ValidationError::new(field, "must not be negative")).extend(),
+            );
+        }
+    }
+
+    let cap = |count: Option<i32>| count.map(|count| count.min(MAX_PAGE_SIZE as i32));
+    Ok((cap(first), cap(last)))
+}
+
+// `ids` are sorted by `id_order`
+// the cursors carry the id, so they stay valid while characters come and go
 async fn query_characters(
     after: Option<String>,
     before: Option<String>,
     first: Option<i32>,
     last: Option<i32>,
-    characters: &[usize],
-) -> async_graphql::Result<Connection<usize, usize, EmptyFields, EmptyFields>> {
+    ids: &[String],
+) -> async_graphql::Result<Connection<OpaqueCursor, String, EmptyFields, EmptyFields>> {
+    let (first, last) = page_size(first, last)?;
+
     query(
         after,
         before,
         first,
         last,
-        |after, before, first, last| async move {
-            let mut start = 0usize;
-            let mut end = characters.len();
+        |after: Option<OpaqueCursor>, before: Option<OpaqueCursor>, first, last| async move {
+            let keys: Vec<_> = ids.iter().map(|id| id_order(id)).collect();
+            let after = after.as_ref().map(|cursor| id_order(&cursor.0));
+            let before = before.as_ref().map(|cursor| id_order(&cursor.0));
+            let page = pagination::page(&keys, after.as_ref(), before.as_ref(), first, last);
 
-            if let Some(after) = after {
-                if after >= characters.len() {
-                    return Ok(Connection::new(false, false));
-                }
-                start = after + 1;
-            }
+            let mut connection = Connection::new(page.has_previous_page, page.has_next_page);
+            connection.append(
+                ids[page.start..page.end]
+                    .iter()
+                    .map(|id| Edge::new(OpaqueCursor(id.clone()), id.clone())),
+            );
+            Ok::<_, async_graphql::Error>(connection)
+        },
+    )
+    .await
+}
 
-            if let Some(before) = before {
-                if before == 0 {
-                    return Ok(Connection::new(false, false));
-                }
-                end = before;
-            }
+// where a character sits in the search results
+// its cursor carries the value it is sorted by and its id,
+// like the id alone for `humans` and `droids`
+#[derive(PartialEq, Eq)]
+struct SearchKey {
+    value: String,
+    id: String,
+    descending: bool,
+}
 
-            let mut slice = &characters[start..end];
+impl SearchKey {
+    fn new(order: CharacterOrder, character: &StarWarsChar) -> Self {
+        let value = match order {
+            CharacterOrder::IdAsc | CharacterOrder::IdDesc => String::new(),
+            CharacterOrder::NameAsc | CharacterOrder::NameDesc => character.name.to_lowercase(),
+        };
+        Self::with_value(order, value, character.id.clone())
+    }
 
-            if let Some(first) = first {
-                slice = &slice[..first.min(slice.len())];
-                end -= first.min(slice.len());
-            } else if let Some(last) = last {
-                slice = &slice[slice.len() - last.min(slice.len())..];
-                start = end - last.min(slice.len());
-            }
+    fn with_value(order: CharacterOrder, value: String, id: String) -> Self {
+        let descending = matches!(order, CharacterOrder::IdDesc | CharacterOrder::NameDesc);
+        Self {
+            value,
+            id,
+            descending,
+        }
+    }
+
+    fn decode(order: CharacterOrder, cursor: &OpaqueCursor) -> async_graphql::Result<Self> {
+        let (value, id): (String, String) =
+            serde_json::from_str(&cursor.0).map_err(|_| "invalid cursor")?;
+        Ok(Self::with_value(order, value, id))
+    }
+
+    fn cursor(&self) -> OpaqueCursor {
+        OpaqueCursor(serde_json::to_string(&(&self.value, &self.id)).expect("serializable key"))
+    }
+}
+
+impl Ord for SearchKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let order = (&self.value, id_order(&self.id)).cmp(&(&other.value, id_order(&other.id)));
+        if self.descending {
+            order.reverse()
+        } else {
+            order
+        }
+    }
+}
+
+impl PartialOrd for SearchKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// `found` is in no particular order, it is sorted here
+async fn search_characters(
+    after: Option<String>,
+    before: Option<String>,
+    first: Option<i32>,
+    last: Option<i32>,
+    order: CharacterOrder,
+    found: Vec<StarWarsChar>,
+) -> async_graphql::Result<Connection<OpaqueCursor, Character, EmptyFields, EmptyFields>> {
+    let (first, last) = page_size(first, last)?;
+
+    let mut found: Vec<_> = found
+        .into_iter()
+        .map(|character| (SearchKey::new(order, &character), character))
+        .collect();
+    found.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let (keys, found): (Vec<_>, Vec<_>) = found.into_iter().unzip();
 
-            let mut connection = Connection::new(start > 0, end < characters.len());
+    query(
+        after,
+        before,
+        first,
+        last,
+        |after: Option<OpaqueCursor>, before: Option<OpaqueCursor>, first, last| async move {
+            let after = after.map(|c| SearchKey::decode(order, &c)).transpose()?;
+            let before = before.map(|c| SearchKey::decode(order, &c)).transpose()?;
+            let page = pagination::page(&keys, after.as_ref(), before.as_ref(), first, last);
+
+            let mut connection = Connection::new(page.has_previous_page, page.has_next_page);
             connection.append(
-                slice
+                keys[page.start..page.end]
                     .iter()
-                    .enumerate()
-                    .map(|(idx, item)| Edge::new(start + idx, *item)),
+                    .zip(&found[page.start..page.end])
+                    .map(|(key, character)| {
+                        Edge::new(
+                            key.cursor(),
+                            Character::new(character.id.clone(), character.kind),
+                        )
+                    }),
             );
-            Ok(connection)
+            Ok::<_, async_graphql::Error>(connection)
         },
     )
     .await
 }
 
-pub type StarWarsSchema = Schema<QueryRoot, EmptyMutation, EmptySubscription>;
+#[derive(MergedObject)]
+pub struct Query(QueryRoot, UserQuery);
 
-pub struct StarWarsChar {
-    id: &'static str,
-    name: &'static str,
-    friends: Vec<usize>,
+#[derive(MergedObject)]
+pub struct Mutation(MutationRoot, UserMutation);
+
+pub type StarWarsSchema = Schema<Query, Mutation, SubscriptionRoot>;
+
+#[derive(Display)]
+pub enum CharacterError {
+    #[display(fmt = "no character with id `{}`", _0)]
+    NotFound(String),
+    // a character that was deleted while the query was resolving it
+    #[display(fmt = "the character was deleted")]
+    Deleted,
+    #[display(fmt = "{}", _0)]
+    Validation(ValidationError),
+    #[display(fmt = "{}", _0)]
+    Storage(UserError),
+}
+
+impl From<ValidationError> for CharacterError {
+    fn from(e: ValidationError) -> Self {
+        Self::Validation(e)
+    }
+}
+
+impl From<UserError> for CharacterError {
+    fn from(e: UserError) -> Self {
+        Self::Storage(e)
+    }
+}
+
+impl From<DieselError> for CharacterError {
+    fn from(e: DieselError) -> Self {
+        Self::Storage(e.into())
+    }
+}
+
+impl ErrorExtensions for CharacterError {
+    fn extend(&self) -> Error {
+        let (code, field) = match self {
+            Self::NotFound(_) | Self::Deleted => ("NOT_FOUND", None),
+            Self::Validation(v) => ("BAD_USER_INPUT", Some(v.field)),
+            // logged, and kept from the client, like the storage errors of the users
+            Self::Storage(e) => return e.extend(),
+        };
+
+        Error::new(self.to_string()).extend_with(|_, e| {
+            e.set("code", code);
+            if let Some(field) = field {
+                e.set("field", field);
+            }
+        })
+    }
+}
+
+// the file the memory storage loads the characters from, in json or yaml
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct Dataset {
+    // the hero of the saga, and of every episode without one of its own
+    hero: String,
+    #[serde(default)]
+    episode_heroes: HashMap<Episode, String>,
+    characters: Vec<CharacterData>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct CharacterData {
+    id: String,
+    name: String,
+    kind: CharacterKind,
+    #[serde(default)]
+    friends: Vec<String>,
+    #[serde(default)]
     appears_in: Vec<Episode>,
-    home_planet: Option<&'static str>,
-    primary_function: Option<&'static str>,
+    home_planet: Option<String>,
+    primary_function: Option<String>,
+}
+
+// every problem with a dataset, so a broken file can be fixed in one go
+#[derive(Debug)]
+pub struct DatasetError(Vec<String>);
+
+impl fmt::Display for DatasetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid starwars dataset:")?;
+        for problem in &self.0 {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for DatasetError {}
+
+// a character as every storage hands it out
+#[derive(Clone)]
+pub struct StarWarsChar {
+    pub id: String,
+    pub name: String,
+    pub kind: CharacterKind,
+    // ids, sorted by `id_order`
+    pub friends: Vec<String>,
+    // sorted, without duplicates
+    pub appears_in: Vec<Episode>,
+    pub home_planet: Option<String>,
+    pub primary_function: Option<String>,
+}
+
+impl StarWarsChar {
+    fn apply(&mut self, changes: CharacterChanges) {
+        if let Some(name) = changes.name {
+            self.name = name;
+        }
+        if let Some(appears_in) = changes.appears_in {
+            self.appears_in = appears_in;
+        }
+        if let Some(home_planet) = changes.home_planet {
+            self.home_planet = home_planet;
+        }
+        if let Some(primary_function) = changes.primary_function {
+            self.primary_function = primary_function;
+        }
+    }
+}
+
+struct Characters {
+    hero: String,
+    episode_heroes: HashMap<Episode, String>,
+    chars: HashMap<String, StarWarsChar>,
+}
+
+impl Characters {
+    fn load(path: &Path) -> Result<Self, DatasetError> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| DatasetError(vec![format!("could not read {}: {}", path.display(), e)]))?;
+
+        let yaml = matches!(
+            path.extension().and_then(|e| e.to_str()),
+            Some("yaml") | Some("yml")
+        );
+        let dataset = if yaml {
+            serde_yaml::from_str(&content).map_err(|e| e.to_string())
+        } else {
+            serde_json::from_str(&content).map_err(|e| e.to_string())
+        };
+        let dataset = dataset.map_err(|e| {
+            DatasetError(vec![format!("could not parse {}: {}", path.display(), e)])
+        })?;
+
+        let characters = Self::from_dataset(dataset)?;
+        info!(
+            "loaded {} starwars characters from {}",
+            characters.chars.len(),
+            path.display()
+        );
+        Ok(characters)
+    }
+
+    fn from_dataset(dataset: Dataset) -> Result<Self, DatasetError> {
+        let mut problems = vec![];
+        let mut chars = HashMap::with_capacity(dataset.characters.len());
+
+        // the friends are resolved once every id is known
+        let mut friend_ids = Vec::with_capacity(dataset.characters.len());
+        for data in dataset.characters {
+            let id = data.id.trim().to_string();
+            let mut problem =
+                |message: &dyn fmt::Display| problems.push(format!("`{}`: {}", id, message));
+
+            let taken = chars.contains_key(&id);
+            if id.is_empty() {
+                problem(&"the id must not be empty");
+            } else if taken {
+                problem(&"the id is taken by another character");
+            }
+            let name = validation::name("name", data.name).unwrap_or_else(|e| {
+                problem(&format_args!("name {}", e.message));
+                String::new()
+            });
+            match data.kind {
+                CharacterKind::Human if data.primary_function.is_some() => {
+                    problem(&"humans have no primary function")
+                }
+                CharacterKind::Droid if data.home_planet.is_some() => {
+                    problem(&"droids have no home planet")
+                }
+                _ => {}
+            }
+
+            if taken {
+                continue;
+            }
+            chars.insert(
+                id.clone(),
+                StarWarsChar {
+                    id: id.clone(),
+                    name,
+                    kind: data.kind,
+                    friends: vec![],
+                    appears_in: episodes(data.appears_in),
+                    home_planet: data.home_planet,
+                    primary_function: data.primary_function,
+                },
+            );
+            friend_ids.push((id, data.friends));
+        }
+
+        let mut characters = Self {
+            hero: String::new(),
+            episode_heroes: HashMap::new(),
+            chars,
+        };
+
+        // listing a friendship on one side is enough, it goes both ways
+        for (id, friends) in friend_ids {
+            for friend_id in friends {
+                if friend_id == id {
+                    problems.push(format!("`{}`: a character can not befriend itself", id));
+                    continue;
+                }
+                match characters.find(&friend_id) {
+                    Ok(_) => {
+                        characters.befriend(&id, &friend_id);
+                    }
+                    Err(e) => problems.push(format!("`{}`: {}", id, e)),
+                }
+            }
+        }
+
+        match characters.find(&dataset.hero) {
+            Ok(_) => characters.hero = dataset.hero,
+            Err(e) => problems.push(format!("hero: {}", e)),
+        }
+        for (episode, id) in dataset.episode_heroes {
+            match characters.find(&id) {
+                Ok(_) => {
+                    characters.episode_heroes.insert(episode, id);
+                }
+                Err(e) => problems.push(format!("hero of {:?}: {}", episode, e)),
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(DatasetError(problems));
+        }
+        Ok(characters)
+    }
+
+    fn is_hero(&self, id: &str) -> bool {
+        id == self.hero || self.episode_heroes.values().any(|hero| hero == id)
+    }
+
+    fn find(&self, id: &str) -> Result<&StarWarsChar, CharacterError> {
+        self.chars
+            .get(id)
+            .ok_or_else(|| CharacterError::NotFound(id.to_string()))
+    }
+
+    // both must exist, returns false when they were friends already
+    fn befriend(&mut self, a: &str, b: &str) -> bool {
+        let added = add_friend_id(&mut self.chars.get_mut(a).expect("known").friends, b);
+        add_friend_id(&mut self.chars.get_mut(b).expect("known").friends, a);
+        added
+    }
+
+    fn unfriend(&mut self, a: &str, b: &str) {
+        if let Some(character) = self.chars.get_mut(a) {
+            character.friends.retain(|f| f != b);
+        }
+        if let Some(character) = self.chars.get_mut(b) {
+            character.friends.retain(|f| f != a);
+        }
+    }
+}
+
+// keeps the friends sorted by `id_order`
+fn add_friend_id(friends: &mut Vec<String>, id: &str) -> bool {
+    match friends.binary_search_by(|f| id_order(f).cmp(&id_order(id))) {
+        Ok(_) => false,
+        Err(pos) => {
+            friends.insert(pos, id.to_string());
+            true
+        }
+    }
+}
+
+fn optional(field: &'static str, value: Option<String>) -> Result<Option<String>, ValidationError> {
+    value.map(|v| validation::name(field, v)).transpose()
+}
+
+fn episodes(mut appears_in: Vec<Episode>) -> Vec<Episode> {
+    appears_in.sort();
+    appears_in.dedup();
+    appears_in
 }
 
+// the memory storage, loaded from the data file
+// the lock is only held for one call, never across an await
+// a clone is a handle to the same data
+#[derive(Clone)]
 pub struct StarWars {
-    luke: usize,
-    artoo: usize,
-    chars: Slab<StarWarsChar>,
-    human_data: HashMap<&'static str, usize>,
-    droid_data: HashMap<&'static str, usize>,
+    data: Arc<RwLock<Characters>>,
 }
 
 impl StarWars {
-    #[allow(clippy::new_without_default)]
-    pub fn new() -> Self {
-        let mut chars = Slab::new();
+    pub fn load(path: &Path) -> Result<Self, DatasetError> {
+        Ok(Self {
+            data: Arc::new(RwLock::new(Characters::load(path)?)),
+        })
+    }
 
-        let luke = chars.insert(StarWarsChar {
-            id: "1000",
-            name: "Luke Skywalker",
-            friends: vec![],
-            appears_in: vec![],
-            home_planet: Some("Tatooine"),
-            primary_function: None,
-        });
+    // swaps in the dataset from the file in one go, readers see the old one or the new one
+    // what the mutations changed since the last load is gone
+    // and if the file does not load, the current dataset stays
+    pub fn reload(&self, path: &Path) -> Result<(), DatasetError> {
+        let characters = Characters::load(path)?;
+        *self.write() = characters;
+        Ok(())
+    }
 
-        let vader = chars.insert(StarWarsChar {
-            id: "1001",
-            name: "Luke Skywalker",
-            friends: vec![],
-            appears_in: vec![],
-            home_planet: Some("Tatooine"),
-            primary_function: None,
-        });
+    fn read(&self) -> RwLockReadGuard<'_, Characters> {
+        self.data.read().expect("starwars data poisoned")
+    }
 
-        let han = chars.insert(StarWarsChar {
-            id: "1002",
-            name: "Han Solo",
-            friends: vec![],
-            appears_in: vec![Episode::Empire, Episode::NewHope, Episode::Jedi],
-            home_planet: None,
-            primary_function: None,
-        });
+    fn write(&self) -> RwLockWriteGuard<'_, Characters> {
+        self.data.write().expect("starwars data poisoned")
+    }
+}
 
-        let leia = chars.insert(StarWarsChar {
-            id: "1003",
-            name: "Leia Organa",
-            friends: vec![],
-            appears_in: vec![Episode::Empire, Episode::NewHope, Episode::Jedi],
-            home_planet: Some("Alderaa"),
-            primary_function: None,
-        });
+#[async_trait]
+impl CharacterRepository for StarWars {
+    async fn find_by_ids(&self, ids: &[String]) -> Result<Vec<StarWarsChar>, CharacterError> {
+        let data = self.read();
+        Ok(ids
+            .iter()
+            .filter_map(|id| data.chars.get(id).cloned())
+            .collect())
+    }
 
-        let tarkin = chars.insert(StarWarsChar {
-            id: "1004",
-            name: "Wilhuff Tarkin",
-            friends: vec![],
-            appears_in: vec![Episode::Empire, Episode::NewHope, Episode::Jedi],
-            home_planet: None,
-            primary_function: None,
-        });
+    async fn search(&self, search: CharacterSearch) -> Result<Vec<StarWarsChar>, CharacterError> {
+        Ok(self
+            .read()
+            .chars
+            .values()
+            .filter(|c| search.matches(c))
+            .cloned()
+            .collect())
+    }
 
-        let threepio = chars.insert(StarWarsChar {
-            id: "2000",
-            name: "C-3PO",
-            friends: vec![],
-            appears_in: vec![Episode::Empire, Episode::NewHope, Episode::Jedi],
-            home_planet: None,
-            primary_function: Some("Protocol"),
-        });
+    async fn ids(&self, kind: CharacterKind) -> Result<Vec<String>, CharacterError> {
+        let mut ids: Vec<_> = self
+            .read()
+            .chars
+            .values()
+            .filter(|c| c.kind == kind)
+            .map(|c| c.id.clone())
+            .collect();
+        ids.sort_by(|a, b| id_order(a).cmp(&id_order(b)));
+        Ok(ids)
+    }
 
-        let artoo = chars.insert(StarWarsChar {
-            id: "2001",
-            name: "R2-D2",
-            friends: vec![],
-            appears_in: vec![Episode::Empire, Episode::NewHope, Episode::Jedi],
-            home_planet: None,
-            primary_function: Some("Astromech"),
-        });
+    async fn hero(&self, episode: Option<Episode>) -> Result<String, CharacterError> {
+        let data = self.read();
+        Ok(episode
+            .and_then(|episode| data.episode_heroes.get(&episode))
+            .unwrap_or(&data.hero)
+            .clone())
+    }
 
-        chars[luke].friends = vec![han, leia, threepio, artoo];
-        chars[vader].friends = vec![tarkin];
-        chars[han].friends = vec![luke, leia, artoo];
-        chars[leia].friends = vec![luke, han, threepio, artoo];
-        chars[tarkin].friends = vec![vader];
-        chars[threepio].friends = vec![luke, han, leia, artoo];
-        chars[artoo].friends = vec![luke, han, leia];
-
-        let mut human_data = HashMap::new();
-        human_data.insert("1000", luke);
-        human_data.insert("1001", vader);
-        human_data.insert("1002", han);
-        human_data.insert("1003", leia);
-        human_data.insert("1004", tarkin);
-
-        let mut droid_data = HashMap::new();
-        droid_data.insert("2000", threepio);
-        droid_data.insert("2001", artoo);
+    async fn create(
+        &self,
+        mut character: StarWarsChar,
+        friends: Vec<String>,
+    ) -> Result<String, CharacterError> {
+        let mut data = self.write();
+        for friend in &friends {
+            data.find(friend)?;
+        }
 
-        Self {
-            luke,
-            artoo,
-            chars,
-            human_data,
-            droid_data,
+        let existing: Vec<_> = data
+            .chars
+            .values()
+            .map(|c| (c.id.clone(), c.kind))
+            .collect();
+        character.id = next_id(character.kind, &existing);
+        character.friends = vec![];
+        let id = character.id.clone();
+        data.chars.insert(id.clone(), character);
+        for friend in &friends {
+            data.befriend(&id, friend);
         }
+
+        Ok(id)
     }
 
-    pub fn human(&self, id: &str) -> Option<usize> {
-        self.human_data.get(id).cloned()
+    async fn update(&self, id: String, changes: CharacterChanges) -> Result<(), CharacterError> {
+        let mut data = self.write();
+        let character = data
+            .chars
+            .get_mut(&id)
+            .ok_or(CharacterError::NotFound(id))?;
+        character.apply(changes);
+        Ok(())
     }
 
-    pub fn droid(&self, id: &str) -> Option<usize> {
-        self.droid_data.get(id).cloned()
+    async fn add_friend(&self, id: String, friend_id: String) -> Result<bool, CharacterError> {
+        let mut data = self.write();
+        data.find(&id)?;
+        data.find(&friend_id)?;
+        Ok(data.befriend(&id, &friend_id))
     }
 
-    pub fn humans(&self) -> Vec<usize> {
-        self.human_data.values().cloned().collect()
+    async fn remove_friend(&self, id: String, friend_id: String) -> Result<(), CharacterError> {
+        let mut data = self.write();
+        data.find(&id)?;
+        data.find(&friend_id)?;
+        data.unfriend(&id, &friend_id);
+        Ok(())
     }
 
-    pub fn droids(&self) -> Vec<usize> {
-        self.droid_data.values().cloned().collect()
+    async fn delete(&self, id: String) -> Result<(), CharacterError> {
+        let mut data = self.write();
+        data.find(&id)?;
+        if data.is_hero(&id) {
+            return Err(
+                ValidationError::new("id", "the heroes of the saga can not be deleted").into(),
+            );
+        }
+
+        let character = data.chars.remove(&id).expect("found");
+        for friend in &character.friends {
+            data.unfriend(&id, friend);
+        }
+        Ok(())
+    }
+}
+
+pub fn make_schema(config: &GraphqlConfig) -> StarWarsSchema {
+    let builder = Schema::build(
+        Query(QueryRoot, UserQuery),
+        Mutation(MutationRoot, UserMutation),
+        SubscriptionRoot,
+    )
+    .data(CharacterEvents::new())
+    .limit_depth(config.max_depth)
+    .limit_complexity(config.max_complexity);
+
+    if config.introspection {
+        builder.finish()
+    } else {
+        builder.disable_introspection().finish()
     }
 }
 
+// the schema in the graphql schema language, for client codegen
+// it does not depend on the config, so it needs none
+pub fn sdl() -> String {
+    Schema::new(
+        Query(QueryRoot, UserQuery),
+        Mutation(MutationRoot, UserMutation),
+        SubscriptionRoot,
+    )
+    .sdl()
+}
+
 pub trait SchemaGetter: Interface {
     fn get(&self) -> &StarWarsSchema;
+    // how long a query or mutation over http may run
+    fn timeout(&self) -> Duration;
+    fn playground(&self) -> bool;
 }
 
 #[derive(Component)]
@@ -372,30 +1551,319 @@ pub trait SchemaGetter: Interface {
 pub struct SchemaGetterImpl {
     #[shaku(default = unimplemented!())]
     schema: StarWarsSchema,
+    #[shaku(default = unimplemented!())]
+    timeout: Duration,
+    #[shaku(default = unimplemented!())]
+    playground: bool,
 }
 
 impl SchemaGetter for SchemaGetterImpl {
     fn get(&self) -> &StarWarsSchema {
         &self.schema
     }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn playground(&self) -> bool {
+        self.playground
+    }
 }
 
+// the request data plus the loaders for the characters
+fn operation_data(
+    characters: Arc<dyn CharacterRepository>,
+    users: Arc<dyn UserRepository>,
+    claims: Option<Claims>,
+    cache: bool,
+) -> Data {
+    let mut data = request_data(users, claims, cache);
+    data.insert(data_loader(
+        CharactersById {
+            characters: characters.clone(),
+        },
+        cache,
+    ));
+    data.insert(characters);
+    data
+}
+
+// depth and complexity are checked before anything runs
+// the timeout catches what slips through, like a slow database
+async fn execute(
+    schema: &dyn SchemaGetter,
+    persisted: &dyn PersistedQueries,
+    mut req: async_graphql::Request,
+) -> async_graphql::Response {
+    let extensions = serde_json::to_value(&req.extensions).ok();
+    if let Err(e) = persisted.resolve(&mut req.query, extensions.as_ref()) {
+        return async_graphql::Response::from_errors(vec![e.extend().into_server_error()]);
+    }
+
+    let timeout = schema.timeout();
+    match tokio::time::timeout(timeout, schema.get().execute(req)).await {
+        Ok(res) => res,
+        Err(_) => {
+            let error = Error::new(format!("the query did not finish within {:?}", timeout))
+                .extend_with(|_, e| e.set("code", "TIMEOUT"));
+            async_graphql::Response::from_errors(vec![error.into_server_error()])
+        }
+    }
+}
+
+// the bearer token is optional here
+// the fields that need one check the claims themselves
 #[handler({
-    container: Container
+    container: Container,
+    middleware: {
+        request: [authenticate()]
+    }
 })]
 async fn starwars_get(
     #[inject] schema: Arc<dyn SchemaGetter>,
+    #[inject] characters: Arc<dyn CharacterRepository>,
+    #[inject] users: Arc<dyn UserRepository>,
+    #[middleware::request(0)] claims: Option<Claims>,
+    #[inject] persisted: Arc<dyn PersistedQueries>,
     #[query] req: GraphQLBody<Request>,
 ) -> Response {
-    schema.get().execute(req.0.into_inner()).await.into()
+    let mut req = req.0.into_inner();
+    req.data = operation_data(characters, users, claims, true);
+    execute(schema.as_ref(), persisted.as_ref(), req)
+        .await
+        .into()
 }
 
 #[handler({
-    container: Container
+    container: Container,
+    middleware: {
+        request: [authenticate()]
+    }
 })]
 async fn starwars_post(
     #[inject] schema: Arc<dyn SchemaGetter>,
+    #[inject] characters: Arc<dyn CharacterRepository>,
+    #[inject] users: Arc<dyn UserRepository>,
+    #[middleware::request(0)] claims: Option<Claims>,
+    #[inject] persisted: Arc<dyn PersistedQueries>,
     #[body] req: GraphQLBody<Request>,
 ) -> Response {
-    schema.get().execute(req.0.into_inner()).await.into()
+    let mut req = req.0.into_inner();
+    req.data = operation_data(characters, users, claims, true);
+    execute(schema.as_ref(), persisted.as_ref(), req)
+        .await
+        .into()
+}
+
+#[derive(Debug, Display)]
+pub enum WsError {
+    #[display(fmt = "expected a websocket upgrade request")]
+    NotUpgrade,
+    #[display(fmt = "expected the graphql-ws or graphql-transport-ws subprotocol")]
+    UnsupportedProtocol,
+}
+
+impl ResponderError for WsError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+}
+
+// the token of a websocket connection
+// browsers can't set headers on the upgrade request, so it can come in `connection_init`
+fn init_token(payload: &serde_json::Value) -> Option<&str> {
+    payload
+        .get("Authorization")
+        .or_else(|| payload.get("authorization"))
+        .and_then(|h| h.as_str())
+        .map(|h| h.strip_prefix("Bearer ").unwrap_or(h))
+}
+
+// async-graphql runs what comes over the websocket itself, out of reach of the timeout
+// of `execute`, so queries and mutations have to go over http
+// a message that starts one ends the connection
+fn subscriptions_only(message: Vec<u8>) -> Result<Vec<u8>, String> {
+    let parsed: serde_json::Value = match serde_json::from_slice(&message) {
+        Ok(parsed) => parsed,
+        Err(_) => return Ok(message),
+    };
+    match parsed.get("type").and_then(serde_json::Value::as_str) {
+        Some("start") | Some("subscribe") => {}
+        _ => return Ok(message),
+    }
+
+    let query = parsed
+        .pointer("/payload/query")
+        .and_then(serde_json::Value::as_str)
+        .unwrap_or_default();
+    // a document that does not parse is reported to the client as usual
+    let document = match parse_query(query) {
+        Ok(document) => document,
+        Err(_) => return Ok(message),
+    };
+    let only_subscriptions = match &document.operations {
+        DocumentOperations::Single(op) => matches!(op.node.ty, OperationType::Subscription),
+        DocumentOperations::Multiple(ops) => ops
+            .values()
+            .all(|op| matches!(op.node.ty, OperationType::Subscription)),
+    };
+
+    if only_subscriptions {
+        Ok(message)
+    } else {
+        Err("queries and mutations go to /starwars, not over the websocket".to_string())
+    }
+}
+
+// subscriptions over a websocket
+// speaks both the graphql-ws (subscriptions-transport-ws)
+// and the graphql-transport-ws protocol, whichever the client asks for
+#[handler({
+    container: Container,
+    middleware: {
+        request: [authenticate()]
+    }
+})]
+async fn starwars_ws(
+    #[request] req: darpi::Request<Body>,
+    #[inject] schema: Arc<dyn SchemaGetter>,
+    #[inject] characters: Arc<dyn CharacterRepository>,
+    #[inject] users: Arc<dyn UserRepository>,
+    #[inject] keys: Arc<dyn JwtKeys>,
+    #[inject] persisted: Arc<dyn PersistedQueries>,
+    #[middleware::request(0)] claims: Option<Claims>,
+) -> Result<darpi::Response<Body>, WsError> {
+    let headers = req.headers();
+    let is_upgrade = headers
+        .get("upgrade")
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|h| h.eq_ignore_ascii_case("websocket"));
+    let key = match headers.get("sec-websocket-key") {
+        Some(key) if is_upgrade => derive_accept_key(key.as_bytes()),
+        _ => return Err(WsError::NotUpgrade),
+    };
+
+    let (name, protocol) = headers
+        .get_all("sec-websocket-protocol")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .map(str::trim)
+        .find_map(|p| Some((p.to_string(), WebSocketProtocols::from_str(p).ok()?)))
+        .ok_or(WsError::UnsupportedProtocol)?;
+
+    let schema = schema.get().clone();
+    tokio::spawn(async move {
+        let upgraded = match hyper::upgrade::on(req).await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                warn!("websocket upgrade failed: {}", e);
+                return;
+            }
+        };
+
+        let socket = WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+        let (mut sink, stream) = socket.split();
+        let input = stream
+            .take_while(|msg| future::ready(msg.is_ok()))
+            .filter_map(|msg| {
+                future::ready(match msg {
+                    Ok(Message::Text(text)) => Some(text.into_bytes()),
+                    Ok(Message::Binary(bytes)) => Some(bytes),
+                    _ => None,
+                })
+            })
+            .map(move |msg| {
+                persisted
+                    .resolve_message(msg)
+                    .map_err(|e| e.to_string())
+                    .and_then(subscriptions_only)
+            })
+            // an operation that is not allowed ends the connection
+            .take_while(|msg| {
+                if let Err(e) = msg {
+                    info!("closing websocket: {}", e);
+                }
+                future::ready(msg.is_ok())
+            })
+            .filter_map(|msg| future::ready(msg.ok()));
+
+        let init = move |payload: serde_json::Value| async move {
+            let claims = match (claims, init_token(&payload)) {
+                (Some(claims), _) => Some(claims),
+                (None, Some(token)) => Some(keys.verify(token).map_err(|e| e.extend())?),
+                (None, None) => None,
+            };
+            // the data lives as long as the connection, so nothing is cached
+            Ok(operation_data(characters, users, claims, false))
+        };
+        let mut output = WebSocket::with_data(schema, input, init, protocol);
+        while let Some(msg) = output.next().await {
+            let msg = match msg {
+                WsMessage::Text(text) => Message::Text(text),
+                WsMessage::Close(code, reason) => Message::Close(Some(CloseFrame {
+                    code: code.into(),
+                    reason: reason.into(),
+                })),
+            };
+            if let Err(e) = sink.send(msg).await {
+                info!("websocket closed: {}", e);
+                break;
+            }
+        }
+        // fails when the client closed it first
+        let _ = sink.close().await;
+    });
+
+    Ok(darpi::Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("connection", "upgrade")
+        .header("upgrade", "websocket")
+        .header("sec-websocket-accept", key)
+        .header("sec-websocket-protocol", name)
+        .body(Body::empty())
+        .expect("valid response"))
+}
+
+#[derive(Debug, Display)]
+#[display(fmt = "the playground is only enabled in development")]
+pub struct PlaygroundDisabled;
+
+impl ResponderError for PlaygroundDisabled {
+    fn respond_err(&self) -> darpi::Response<Body> {
+        error_response(
+            self.status_code(),
+            "not_found",
+            self.to_string(),
+            None,
+            self,
+        )
+    }
+
+    fn status_code(&self) -> StatusCode {
+        StatusCode::NOT_FOUND
+    }
+}
+
+// graphql playground, for trying out queries, mutations and subscriptions in a browser
+// it shows anyone around the api, so it is off unless the config turns it on
+#[handler({
+    container: Container
+})]
+async fn starwars_playground(
+    #[inject] schema: Arc<dyn SchemaGetter>,
+) -> Result<darpi::Response<Body>, PlaygroundDisabled> {
+    if !schema.playground() {
+        return Err(PlaygroundDisabled);
+    }
+
+    let html = playground_source(
+        GraphQLPlaygroundConfig::new("/starwars").subscription_endpoint("/starwars/ws"),
+    );
+    Ok(darpi::Response::builder()
+        .status(StatusCode::OK)
+        .header("content-type", "text/html; charset=utf-8")
+        .body(Body::from(html))
+        .expect("valid response"))
 }
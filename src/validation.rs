@@ -0,0 +1,100 @@
+use derive_more::Display;
+use serde::Serialize;
+
+const MAX_NAME_LEN: usize = 64;
+// the longest address SMTP can deliver to
+const MAX_EMAIL_LEN: usize = 254;
+const MIN_PASSWORD_LEN: usize = 8;
+// bcrypt silently ignores everything past 72 bytes
+const MAX_PASSWORD_LEN: usize = 72;
+
+#[derive(Debug, Display, Serialize)]
+#[display(fmt = "{}: {}", field, message)]
+pub struct ValidationError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl ValidationError {
+    pub(crate) fn new(field: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+// returns the trimmed name
+pub fn name(field: &'static str, value: String) -> Result<String, ValidationError> {
+    let value = value.trim();
+
+    if value.is_empty() {
+        return Err(ValidationError::new(field, "must not be empty"));
+    }
+
+    if value.chars().count() > MAX_NAME_LEN {
+        return Err(ValidationError::new(
+            field,
+            format!("must be at most {} characters", MAX_NAME_LEN),
+        ));
+    }
+
+    Ok(value.to_string())
+}
+
+// returns the normalized address
+// emails are compared case insensitively, so we store them lowercase
+pub fn email(value: String) -> Result<String, ValidationError> {
+    let value = normalize_email(&value);
+
+    if value.len() > MAX_EMAIL_LEN {
+        return Err(ValidationError::new(
+            "email",
+            format!("must be at most {} characters", MAX_EMAIL_LEN),
+        ));
+    }
+
+    if !is_email(&value) {
+        return Err(ValidationError::new("email", "is not a valid email address"));
+    }
+
+    Ok(value)
+}
+
+pub fn password(value: &str) -> Result<(), ValidationError> {
+    if value.len() < MIN_PASSWORD_LEN {
+        return Err(ValidationError::new(
+            "password",
+            format!("must be at least {} characters", MIN_PASSWORD_LEN),
+        ));
+    }
+
+    if value.len() > MAX_PASSWORD_LEN {
+        return Err(ValidationError::new(
+            "password",
+            format!("must be at most {} bytes", MAX_PASSWORD_LEN),
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn normalize_email(value: &str) -> String {
+    value.trim().to_lowercase()
+}
+
+// intentionally loose
+// the only real validation of an address is sending mail to it
+fn is_email(value: &str) -> bool {
+    if value.chars().any(char::is_whitespace) {
+        return false;
+    }
+
+    let mut parts = value.split('@');
+    let (local, domain) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(local), Some(domain), None) => (local, domain),
+        _ => return false,
+    };
+
+    !local.is_empty() && domain.contains('.') && domain.split('.').all(|label| !label.is_empty())
+}
@@ -0,0 +1,1576 @@
+use darpi::chrono::Duration;
+use darpi::App;
+use example_heroku_darpi::config::{
+    self, Config, GraphqlConfig, JwtConfig, JwtKeyConfig, StarWarsConfig, StorageConfig,
+};
+use example_heroku_darpi::memory::MemoryStore;
+use example_heroku_darpi::middleware::Role;
+use example_heroku_darpi::models::{self, NewUser, User};
+use example_heroku_darpi::repository::UserRepository;
+use example_heroku_darpi::starwars::StarWars;
+use example_heroku_darpi::{make_app, make_container_with, Storage};
+use futures_util::{SinkExt, Stream, StreamExt};
+use jsonwebtoken::Algorithm;
+use reqwest::{Client, StatusCode};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::net::TcpListener;
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+
+const ADMIN_EMAIL: &str = "admin@example.com";
+const USER_EMAIL: &str = "user@example.com";
+const PASSWORD: &str = "correct horse battery";
+
+fn test_config() -> Config {
+    Config {
+        address: "127.0.0.1:0".to_string(),
+        log_level: "off".to_string(),
+        body_limit: config::DEFAULT_BODY_LIMIT,
+        storage: StorageConfig::Memory,
+        jwt: JwtConfig {
+            algorithm: Algorithm::HS256,
+            key_id: "test".to_string(),
+            keys: JwtKeyConfig::Secret("test secret".to_string()),
+            expiry: Duration::minutes(5),
+            refresh_expiry: Duration::days(1),
+        },
+        graphql: GraphqlConfig {
+            max_depth: 6,
+            max_complexity: 500,
+            timeout: std::time::Duration::from_secs(5),
+            apq_cache_size: 16,
+            allowlist_dir: None,
+            introspection: true,
+            playground: true,
+        },
+        starwars: StarWarsConfig {
+            data_file: "data/starwars.json".into(),
+            reload: None,
+        },
+    }
+}
+
+// false when the app stopped, it could not bind the address
+async fn listening<T>(address: &str, server: &tokio::task::JoinHandle<T>) -> bool {
+    for _ in 0..50 {
+        if server.is_finished() {
+            return false;
+        }
+        if tokio::net::TcpStream::connect(address).await.is_ok() {
+            return true;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    panic!(
+        "the test app is not listening on {} after a second",
+        address
+    );
+}
+
+struct TestApp {
+    base: String,
+    client: Client,
+}
+
+impl TestApp {
+    async fn spawn() -> Self {
+        Self::spawn_with(test_config()).await
+    }
+
+    async fn spawn_with(config: Config) -> Self {
+        let store = MemoryStore::default();
+        for (email, role) in &[(ADMIN_EMAIL, Role::Admin), (USER_EMAIL, Role::User)] {
+            UserRepository::create(
+                &store,
+                NewUser {
+                    first_name: "Test".to_string(),
+                    last_name: "User".to_string(),
+                    email: email.to_string(),
+                    password: PASSWORD.to_string(),
+                    role: *role,
+                },
+            )
+            .await
+            .expect("seed user");
+        }
+        let starwars = StarWars::load(&config.starwars.data_file).expect("test dataset");
+        let storage = Storage::Memory(store, starwars);
+
+        // darpi binds the address itself and takes no listener,
+        // so when another process grabs the port first the app gives up and we try another
+        for _ in 0..5 {
+            let port = TcpListener::bind("127.0.0.1:0")
+                .and_then(|l| l.local_addr())
+                .expect("free port")
+                .port();
+            let address = format!("127.0.0.1:{}", port);
+
+            let app = make_app(
+                address.clone(),
+                make_container_with(&config, storage.clone()),
+            );
+            let server = tokio::spawn(app.run());
+            if listening(&address, &server).await {
+                return Self {
+                    base: format!("http://{}", address),
+                    client: Client::new(),
+                };
+            }
+        }
+        panic!("the test app could not bind a port");
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+
+    async fn login(&self, email: &str) -> String {
+        let pair = self.token_pair(email).await;
+        pair["access_token"].as_str().unwrap().to_string()
+    }
+
+    async fn token_pair(&self, email: &str) -> Value {
+        let res = self
+            .client
+            .post(&self.url("/login"))
+            .json(&json!({ "email": email, "password": PASSWORD }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        res.json().await.unwrap()
+    }
+
+    async fn refresh(&self, refresh_token: &Value) -> reqwest::Response {
+        self.client
+            .post(&self.url("/token/refresh"))
+            .json(&json!({ "refresh_token": refresh_token }))
+            .send()
+            .await
+            .unwrap()
+    }
+
+    async fn graphql(&self, query: &str) -> Value {
+        self.graphql_as(None, query).await
+    }
+
+    async fn graphql_as(&self, token: Option<&str>, query: &str) -> Value {
+        let mut req = self
+            .client
+            .post(&self.url("/starwars"))
+            .json(&json!({ "query": query }));
+        if let Some(token) = token {
+            req = req.bearer_auth(token);
+        }
+
+        let res = req.send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        res.json().await.unwrap()
+    }
+
+    async fn graphql_body(&self, body: Value) -> Value {
+        let res = self
+            .client
+            .post(&self.url("/starwars"))
+            .json(&body)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        res.json().await.unwrap()
+    }
+
+    async fn create_user(&self, token: Option<&str>, email: &str) -> reqwest::Response {
+        let mut req = self.client.post(&self.url("/user")).json(&json!({
+            "first_name": "Han",
+            "last_name": "Solo",
+            "email": email,
+            "password": PASSWORD,
+        }));
+        if let Some(token) = token {
+            req = req.bearer_auth(token);
+        }
+        req.send().await.unwrap()
+    }
+}
+
+#[tokio::test]
+async fn home() {
+    let app = TestApp::spawn().await;
+
+    let res = app.client.get(&app.url("/")).send().await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.text().await.unwrap(), "Welcome to darpi");
+}
+
+#[tokio::test]
+async fn login_with_a_wrong_password() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .client
+        .post(&app.url("/login"))
+        .json(&json!({ "email": ADMIN_EMAIL, "password": "wrong password" }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "invalid_credentials");
+}
+
+#[tokio::test]
+async fn login_with_an_unknown_email() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .client
+        .post(&app.url("/login"))
+        .json(&json!({ "email": "nobody@example.com", "password": "wrong password" }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "invalid_credentials");
+}
+
+// users from before passwords were stored have an empty hash
+#[test]
+fn an_unreadable_hash_is_a_mismatch() {
+    let user = User {
+        id: 1,
+        first_name: "Ben".to_string(),
+        last_name: "Kenobi".to_string(),
+        email: "ben@example.com".to_string(),
+        password_hash: String::new(),
+        role: Role::User,
+    };
+
+    assert!(!models::verify_password("", Some(&user)));
+    assert!(!models::verify_password("password", None));
+}
+
+#[tokio::test]
+async fn login_returns_a_token_pair() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .client
+        .post(&app.url("/login"))
+        .json(&json!({ "email": USER_EMAIL, "password": PASSWORD }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["token_type"], "Bearer");
+    assert_eq!(body["expires_in"], 300);
+    assert!(body["refresh_token"].is_string());
+}
+
+#[tokio::test]
+async fn refresh_rotates_the_token_pair() {
+    let app = TestApp::spawn().await;
+    let first = app.token_pair(USER_EMAIL).await;
+
+    let res = app.refresh(&first["refresh_token"]).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let second: Value = res.json().await.unwrap();
+    assert_eq!(second["token_type"], "Bearer");
+    assert!(second["refresh_token"].is_string());
+    assert_ne!(second["refresh_token"], first["refresh_token"]);
+
+    // the new access token is as good as the one from the login
+    let res = app
+        .client
+        .get(&app.url("/users"))
+        .bearer_auth(second["access_token"].as_str().unwrap())
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    // the old refresh token was spent
+    let res = app.refresh(&first["refresh_token"]).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "invalid_refresh_token");
+}
+
+#[tokio::test]
+async fn a_replayed_refresh_token_revokes_its_family() {
+    let app = TestApp::spawn().await;
+    let first = app.token_pair(USER_EMAIL).await;
+    let other_login = app.token_pair(USER_EMAIL).await;
+
+    let second: Value = app
+        .refresh(&first["refresh_token"])
+        .await
+        .json()
+        .await
+        .unwrap();
+
+    // someone replays the token that was already rotated
+    let res = app.refresh(&first["refresh_token"]).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    // so the newest token of that login is revoked too
+    let res = app.refresh(&second["refresh_token"]).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    // other logins of the same user keep working
+    let res = app.refresh(&other_login["refresh_token"]).await;
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn logout_revokes_the_refresh_token() {
+    let app = TestApp::spawn().await;
+    let pair = app.token_pair(USER_EMAIL).await;
+
+    let res = app
+        .client
+        .post(&app.url("/logout"))
+        .json(&json!({ "refresh_token": pair["refresh_token"] }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = app.refresh(&pair["refresh_token"]).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    // a token we never issued can not be logged out
+    let res = app
+        .client
+        .post(&app.url("/logout"))
+        .json(&json!({ "refresh_token": "not a token" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn an_expired_refresh_token_is_rejected() {
+    let mut config = test_config();
+    // already expired when it is handed out
+    config.jwt.refresh_expiry = Duration::seconds(-1);
+    let app = TestApp::spawn_with(config).await;
+    let pair = app.token_pair(USER_EMAIL).await;
+
+    let res = app.refresh(&pair["refresh_token"]).await;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "invalid_refresh_token");
+}
+
+#[tokio::test]
+async fn admin_creates_and_gets_a_user() {
+    let app = TestApp::spawn().await;
+    let token = app.login(ADMIN_EMAIL).await;
+
+    let res = app.create_user(Some(&token), "han@example.com").await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let created: Value = res.json().await.unwrap();
+    assert!(created.get("password").is_none());
+    assert!(created.get("password_hash").is_none());
+
+    let id = created["id"].as_i64().unwrap();
+    let res = app
+        .client
+        .get(&app.url(&format!("/user/{}", id)))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let fetched: Value = res.json().await.unwrap();
+    assert_eq!(fetched, created);
+}
+
+#[tokio::test]
+async fn get_a_missing_user() {
+    let app = TestApp::spawn().await;
+    let token = app.login(USER_EMAIL).await;
+
+    let res = app
+        .client
+        .get(&app.url("/user/9999"))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "not_found");
+}
+
+#[tokio::test]
+async fn get_user_needs_a_token() {
+    let app = TestApp::spawn().await;
+
+    let res = app.client.get(&app.url("/user/1")).send().await.unwrap();
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+fn emails(users: &Value) -> Vec<&str> {
+    users
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|user| user["email"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn list_users_in_pages() {
+    let app = TestApp::spawn().await;
+    let token = app.login(ADMIN_EMAIL).await;
+    let res = app.create_user(Some(&token), "han@example.com").await;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let res = app.client.get(&app.url("/users")).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    let token = app.login(USER_EMAIL).await;
+    let page = |query: &'static str| {
+        app.client
+            .get(&app.url(&format!("/users{}", query)))
+            .bearer_auth(&token)
+            .send()
+    };
+
+    let res = page("").await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let users: Value = res.json().await.unwrap();
+    assert_eq!(emails(&users), [ADMIN_EMAIL, USER_EMAIL, "han@example.com"]);
+
+    let users: Value = page("?limit=2").await.unwrap().json().await.unwrap();
+    assert_eq!(emails(&users), [ADMIN_EMAIL, USER_EMAIL]);
+
+    let users: Value = page("?limit=2&offset=2")
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(emails(&users), ["han@example.com"]);
+
+    let users: Value = page("?offset=3").await.unwrap().json().await.unwrap();
+    assert!(emails(&users).is_empty());
+}
+
+#[tokio::test]
+async fn patch_changes_only_the_given_fields() {
+    let app = TestApp::spawn().await;
+    let token = app.login(ADMIN_EMAIL).await;
+    let created: Value = app
+        .create_user(Some(&token), "han@example.com")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let url = app.url(&format!("/user/{}", created["id"]));
+
+    let res = app
+        .client
+        .patch(&url)
+        .bearer_auth(&token)
+        .json(&json!({ "last_name": "  Organa " }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let patched: Value = res.json().await.unwrap();
+    assert_eq!(patched["last_name"], "Organa");
+    assert_eq!(patched["first_name"], "Han");
+    assert_eq!(patched["email"], "han@example.com");
+    assert_eq!(patched["role"], "User");
+
+    let res = app
+        .client
+        .put(&url)
+        .bearer_auth(&token)
+        .json(&json!({
+            "first_name": "Leia",
+            "last_name": "Organa",
+            "email": "leia@example.com",
+            "password": "a new long password",
+            "role": "Admin",
+        }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let replaced: Value = res.json().await.unwrap();
+    assert_eq!(replaced["id"], created["id"]);
+    assert_eq!(replaced["first_name"], "Leia");
+    assert_eq!(replaced["email"], "leia@example.com");
+    assert_eq!(replaced["role"], "Admin");
+    assert!(replaced.get("password").is_none());
+
+    // the new password is the one that logs in
+    let res = app
+        .client
+        .post(&app.url("/login"))
+        .json(&json!({ "email": "leia@example.com", "password": "a new long password" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn update_with_invalid_fields() {
+    let app = TestApp::spawn().await;
+    let token = app.login(ADMIN_EMAIL).await;
+
+    let res = app
+        .client
+        .patch(&app.url("/user/2"))
+        .bearer_auth(&token)
+        .json(&json!({ "email": "not an email" }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "invalid_input");
+    assert_eq!(body["field"], "email");
+
+    // nothing was changed
+    let res = app
+        .client
+        .get(&app.url("/user/2"))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    let user: Value = res.json().await.unwrap();
+    assert_eq!(user["email"], USER_EMAIL);
+}
+
+#[tokio::test]
+async fn update_and_delete_a_missing_user() {
+    let app = TestApp::spawn().await;
+    let token = app.login(ADMIN_EMAIL).await;
+
+    let res = app
+        .client
+        .patch(&app.url("/user/9999"))
+        .bearer_auth(&token)
+        .json(&json!({ "first_name": "Nobody" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+    let res = app
+        .client
+        .delete(&app.url("/user/9999"))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "not_found");
+}
+
+#[tokio::test]
+async fn delete_a_user() {
+    let app = TestApp::spawn().await;
+    let token = app.login(ADMIN_EMAIL).await;
+    let created: Value = app
+        .create_user(Some(&token), "han@example.com")
+        .await
+        .json()
+        .await
+        .unwrap();
+    let url = app.url(&format!("/user/{}", created["id"]));
+
+    let res = app
+        .client
+        .delete(&url)
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let deleted: Value = res.json().await.unwrap();
+    assert_eq!(deleted, created);
+
+    let res = app
+        .client
+        .get(&url)
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn update_and_delete_need_the_admin_role() {
+    let app = TestApp::spawn().await;
+    let token = app.login(USER_EMAIL).await;
+
+    let res = app
+        .client
+        .patch(&app.url("/user/2"))
+        .bearer_auth(&token)
+        .json(&json!({ "role": "Admin" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = app
+        .client
+        .put(&app.url("/user/2"))
+        .bearer_auth(&token)
+        .json(&json!({ "role": "Admin" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = app
+        .client
+        .delete(&app.url("/user/1"))
+        .bearer_auth(&token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    let res = app.client.delete(&app.url("/user/1")).send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn create_user_without_a_token() {
+    let app = TestApp::spawn().await;
+
+    let res = app.create_user(None, "han@example.com").await;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn create_user_with_a_forged_token() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .create_user(Some("not.a.token"), "han@example.com")
+        .await;
+
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn create_user_needs_the_admin_role() {
+    let app = TestApp::spawn().await;
+    let token = app.login(USER_EMAIL).await;
+
+    let res = app.create_user(Some(&token), "han@example.com").await;
+
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn create_user_with_a_taken_email() {
+    let app = TestApp::spawn().await;
+    let token = app.login(ADMIN_EMAIL).await;
+
+    let res = app.create_user(Some(&token), USER_EMAIL).await;
+
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["field"], "email");
+}
+
+#[tokio::test]
+async fn body_over_the_limit_is_rejected() {
+    let app = TestApp::spawn().await;
+    let token = app.login(ADMIN_EMAIL).await;
+
+    let res = app
+        .client
+        .post(&app.url("/user"))
+        .bearer_auth(&token)
+        .header("content-type", "application/json")
+        .body(format!(
+            r#"{{"first_name":"{}"}}"#,
+            "x".repeat(config::DEFAULT_BODY_LIMIT as usize * 2)
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "payload_too_large");
+}
+
+#[tokio::test]
+async fn the_limits_come_from_the_config() {
+    let mut config = test_config();
+    config.body_limit = 256;
+    config.jwt.expiry = Duration::minutes(1);
+    let app = TestApp::spawn_with(config).await;
+
+    let res = app
+        .client
+        .post(&app.url("/login"))
+        .json(&json!({ "email": USER_EMAIL, "password": PASSWORD }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["expires_in"], 60);
+
+    let res = app
+        .client
+        .post(&app.url("/login"))
+        .json(&json!({ "email": USER_EMAIL, "password": "x".repeat(256) }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+}
+
+#[tokio::test]
+async fn starwars_hero() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .client
+        .post(&app.url("/starwars"))
+        .json(&json!({ "query": "{ hero(episode: NEW_HOPE) { name } }" }))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["data"]["hero"]["name"], "R2-D2");
+}
+
+#[tokio::test]
+async fn starwars_query_fits_the_default_body_limit() {
+    let app = TestApp::spawn().await;
+    // well past what a small limit would take, as a query from a real client is
+    let query = format!("{}{{ hero {{ name }} }}", " ".repeat(4096));
+
+    let body = app.graphql(&query).await;
+    assert_eq!(body["data"]["hero"]["name"], "R2-D2");
+}
+
+#[tokio::test]
+async fn starwars_query_timeout() {
+    let mut config = test_config();
+    config.graphql.timeout = std::time::Duration::from_secs(0);
+    let app = TestApp::spawn_with(config).await;
+
+    // the loader waits a moment to batch, so this can not finish right away
+    let body = app.graphql(r#"{ human(id: "1000") { name } }"#).await;
+    assert_eq!(body["errors"][0]["extensions"]["code"], "TIMEOUT");
+}
+
+#[tokio::test]
+async fn starwars_hero_of_the_saga() {
+    let app = TestApp::spawn().await;
+
+    let body = app
+        .graphql("{ saga: hero { name } empire: hero(episode: EMPIRE) { name } }")
+        .await;
+    assert_eq!(body["data"]["saga"]["name"], "R2-D2");
+    assert_eq!(body["data"]["empire"]["name"], "Luke Skywalker");
+}
+
+#[tokio::test]
+async fn starwars_films() {
+    let app = TestApp::spawn().await;
+
+    let body = app
+        .graphql("{ films { episode title releaseDate director hero { name } } }")
+        .await;
+    let films = body["data"]["films"].as_array().unwrap();
+    assert_eq!(films.len(), 3);
+    assert_eq!(
+        films[1],
+        json!({
+            "episode": "EMPIRE",
+            "title": "The Empire Strikes Back",
+            "releaseDate": "1980-05-21",
+            "director": "Irvin Kershner",
+            "hero": { "name": "Luke Skywalker" }
+        })
+    );
+
+    // from the cast of a film to the films of one of them
+    let body = app
+        .graphql(
+            r#"{ film(episode: NEW_HOPE) {
+                characters(last: 3) { edges { node { name films { title } } } }
+            } }"#,
+        )
+        .await;
+    let cast = &body["data"]["film"]["characters"];
+    assert_eq!(names(cast), ["Wilhuff Tarkin", "C-3PO", "R2-D2"]);
+    assert_eq!(
+        cast["edges"][0]["node"]["films"],
+        json!([{ "title": "A New Hope" }])
+    );
+}
+
+#[tokio::test]
+async fn starwars_playground() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .client
+        .get(&app.url("/starwars/playground"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert!(res.headers()["content-type"]
+        .to_str()
+        .unwrap()
+        .starts_with("text/html"));
+    let html = res.text().await.unwrap();
+    assert!(html.contains("/starwars/ws"));
+}
+
+#[tokio::test]
+async fn starwars_playground_is_off_by_config() {
+    let mut config = test_config();
+    config.graphql.playground = false;
+    let app = TestApp::spawn_with(config).await;
+
+    let res = app
+        .client
+        .get(&app.url("/starwars/playground"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["code"], "not_found");
+    assert!(body["request_id"].is_string());
+}
+
+#[tokio::test]
+async fn starwars_human_over_get() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .client
+        .get(&app.url("/starwars"))
+        .query(&[("query", r#"{ human(id: "1000") { name homePlanet } }"#)])
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let body: Value = res.json().await.unwrap();
+    assert_eq!(body["data"]["human"]["name"], "Luke Skywalker");
+    assert_eq!(body["data"]["human"]["homePlanet"], "Tatooine");
+}
+
+#[tokio::test]
+async fn starwars_create_and_befriend() {
+    let app = TestApp::spawn().await;
+    let token = app.login(USER_EMAIL).await;
+
+    let body = app
+        .graphql_as(
+            Some(&token),
+            r#"mutation {
+                createHuman(input: { name: " Wedge Antilles ", friends: ["1000"] }) {
+                    id name friends { name }
+                }
+            }"#,
+        )
+        .await;
+    let wedge = &body["data"]["createHuman"];
+    assert_eq!(wedge["name"], "Wedge Antilles");
+    assert_eq!(wedge["friends"], json!([{ "name": "Luke Skywalker" }]));
+
+    let id = wedge["id"].as_str().unwrap();
+    let body = app
+        .graphql_as(
+            Some(&token),
+            &format!(
+                r#"mutation {{ addFriend(id: "{}", friendId: "2001") {{ friends {{ name }} }} }}"#,
+                id
+            ),
+        )
+        .await;
+    assert_eq!(
+        body["data"]["addFriend"]["friends"],
+        json!([{ "name": "Luke Skywalker" }, { "name": "R2-D2" }])
+    );
+
+    // friendships go both ways
+    let body = app
+        .graphql(r#"{ droid(id: "2001") { friends { name } } }"#)
+        .await;
+    let friends = body["data"]["droid"]["friends"].as_array().unwrap();
+    assert!(friends.contains(&json!({ "name": "Wedge Antilles" })));
+}
+
+#[tokio::test]
+async fn starwars_humans_connection() {
+    let app = TestApp::spawn().await;
+    let page = "edges { cursor node { id } } pageInfo { hasPreviousPage hasNextPage }";
+
+    let body = app
+        .graphql(&format!("{{ humans(first: 2) {{ {} }} }}", page))
+        .await;
+    let humans = &body["data"]["humans"];
+    let ids: Vec<_> = humans["edges"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| &e["node"]["id"])
+        .collect();
+    assert_eq!(ids, ["1000", "1001"]);
+    assert_eq!(
+        humans["pageInfo"],
+        json!({ "hasPreviousPage": false, "hasNextPage": true })
+    );
+
+    // cursors are opaque, not the position of the character
+    let after = humans["edges"][1]["cursor"].as_str().unwrap();
+    assert!(after.parse::<usize>().is_err());
+
+    let body = app
+        .graphql(&format!(
+            r#"{{ humans(first: 5, after: "{}") {{ {} }} }}"#,
+            after, page
+        ))
+        .await;
+    let humans = &body["data"]["humans"];
+    let ids: Vec<_> = humans["edges"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| &e["node"]["id"])
+        .collect();
+    assert_eq!(ids, ["1002", "1003", "1004"]);
+    assert_eq!(
+        humans["pageInfo"],
+        json!({ "hasPreviousPage": true, "hasNextPage": false })
+    );
+
+    let before = humans["edges"][0]["cursor"].as_str().unwrap();
+    let body = app
+        .graphql(&format!(
+            r#"{{ humans(last: 1, before: "{}") {{ {} }} }}"#,
+            before, page
+        ))
+        .await;
+    let humans = &body["data"]["humans"];
+    assert_eq!(humans["edges"][0]["node"]["id"], "1001");
+    assert_eq!(
+        humans["pageInfo"],
+        json!({ "hasPreviousPage": true, "hasNextPage": true })
+    );
+}
+
+#[tokio::test]
+async fn starwars_negative_page_size() {
+    let app = TestApp::spawn().await;
+
+    let body = app
+        .graphql("{ droids(last: -1) { edges { cursor } } }")
+        .await;
+    assert_eq!(body["data"], Value::Null);
+    assert_eq!(body["errors"][0]["extensions"]["code"], "BAD_USER_INPUT");
+    assert_eq!(body["errors"][0]["extensions"]["field"], "last");
+}
+
+fn names(connection: &Value) -> Vec<&str> {
+    connection["edges"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|e| e["node"]["name"].as_str().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn starwars_search() {
+    let app = TestApp::spawn().await;
+
+    let body = app
+        .graphql(r#"{ search(text: "SKY") { edges { node { name } } } }"#)
+        .await;
+    assert_eq!(names(&body["data"]["search"]), ["Luke Skywalker"]);
+
+    let body = app
+        .graphql(r#"{ search(homePlanet: " tatooine ") { edges { node { name } } } }"#)
+        .await;
+    assert_eq!(
+        names(&body["data"]["search"]),
+        ["Luke Skywalker", "Darth Vader"]
+    );
+
+    // Tarkin is only in A New Hope
+    let query = |after: &str| {
+        format!(
+            r#"{{ search(episode: EMPIRE, kind: HUMAN, orderBy: NAME_DESC, first: 2{}) {{
+                edges {{ cursor node {{ name }} }} pageInfo {{ hasNextPage }}
+            }} }}"#,
+            after
+        )
+    };
+    let body = app.graphql(&query("")).await;
+    let search = &body["data"]["search"];
+    assert_eq!(names(search), ["Luke Skywalker", "Leia Organa"]);
+    assert_eq!(search["pageInfo"]["hasNextPage"], true);
+
+    let cursor = search["edges"][1]["cursor"].as_str().unwrap();
+    let body = app
+        .graphql(&query(&format!(r#", after: "{}""#, cursor)))
+        .await;
+    let search = &body["data"]["search"];
+    assert_eq!(names(search), ["Han Solo", "Darth Vader"]);
+    assert_eq!(search["pageInfo"]["hasNextPage"], false);
+}
+
+#[tokio::test]
+async fn starwars_friends_of_friends() {
+    let app = TestApp::spawn().await;
+
+    let body = app
+        .graphql(r#"{ human(id: "1004") { name friends { id friends { name } } } }"#)
+        .await;
+    assert_eq!(
+        body["data"]["human"],
+        json!({
+            "name": "Wilhuff Tarkin",
+            "friends": [{ "id": "1001", "friends": [{ "name": "Wilhuff Tarkin" }] }]
+        })
+    );
+}
+
+#[tokio::test]
+async fn starwars_mutations_see_earlier_mutations() {
+    let app = TestApp::spawn().await;
+    let token = app.login(USER_EMAIL).await;
+
+    // the characters loaded for the first mutation are not reused by the second
+    let body = app
+        .graphql_as(
+            Some(&token),
+            r#"mutation {
+                added: addFriend(id: "1004", friendId: "2000") { friends { id } }
+                removed: removeFriend(id: "1004", friendId: "2000") { friends { id } }
+            }"#,
+        )
+        .await;
+    assert_eq!(
+        body["data"]["added"]["friends"],
+        json!([{ "id": "1001" }, { "id": "2000" }])
+    );
+    assert_eq!(
+        body["data"]["removed"]["friends"],
+        json!([{ "id": "1001" }])
+    );
+}
+
+#[tokio::test]
+async fn starwars_invalid_input() {
+    let app = TestApp::spawn().await;
+    let token = app.login(ADMIN_EMAIL).await;
+
+    let body = app
+        .graphql_as(
+            Some(&token),
+            r#"mutation { createDroid(input: { name: "  " }) { id } }"#,
+        )
+        .await;
+    assert_eq!(body["errors"][0]["extensions"]["code"], "BAD_USER_INPUT");
+    assert_eq!(body["errors"][0]["extensions"]["field"], "name");
+
+    let body = app
+        .graphql_as(
+            Some(&token),
+            r#"mutation { updateCharacter(id: "2000", input: { homePlanet: "Tatooine" }) { id } }"#,
+        )
+        .await;
+    assert_eq!(body["errors"][0]["extensions"]["field"], "homePlanet");
+
+    let body = app
+        .graphql_as(Some(&token), r#"mutation { deleteCharacter(id: "9999") }"#)
+        .await;
+    assert_eq!(body["errors"][0]["extensions"]["code"], "NOT_FOUND");
+}
+
+#[tokio::test]
+async fn starwars_delete_character() {
+    let app = TestApp::spawn().await;
+    let token = app.login(ADMIN_EMAIL).await;
+
+    let body = app
+        .graphql_as(Some(&token), r#"mutation { deleteCharacter(id: "1002") }"#)
+        .await;
+    assert_eq!(body["data"]["deleteCharacter"], "1002");
+
+    let body = app
+        .graphql(r#"{ human(id: "1002") { name } luke: human(id: "1000") { friends { name } } }"#)
+        .await;
+    assert_eq!(body["data"]["human"], Value::Null);
+    let friends = body["data"]["luke"]["friends"].as_array().unwrap();
+    assert!(!friends.contains(&json!({ "name": "Han Solo" })));
+}
+
+#[tokio::test]
+async fn starwars_mutations_need_a_role() {
+    let app = TestApp::spawn().await;
+
+    let body = app
+        .graphql(r#"mutation { createDroid(input: { name: "BB-8" }) { id } }"#)
+        .await;
+    assert_eq!(body["data"], Value::Null);
+    assert_eq!(body["errors"][0]["extensions"]["code"], "UNAUTHORIZED");
+
+    let token = app.login(USER_EMAIL).await;
+    let body = app
+        .graphql_as(Some(&token), r#"mutation { deleteCharacter(id: "1002") }"#)
+        .await;
+    assert_eq!(body["errors"][0]["extensions"]["code"], "UNAUTHORIZED");
+    assert_eq!(body["errors"][0]["message"], "insufficient permissions");
+
+    // queries stay public
+    let body = app.graphql(r#"{ human(id: "1002") { name } }"#).await;
+    assert_eq!(body["data"]["human"]["name"], "Han Solo");
+}
+
+async fn receive<S>(socket: &mut S) -> Value
+where
+    S: Stream<Item = Result<Message, WsError>> + Unpin,
+{
+    match socket.next().await {
+        Some(Ok(Message::Text(text))) => serde_json::from_str(&text).unwrap(),
+        msg => panic!("unexpected message {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn starwars_subscription() {
+    let app = TestApp::spawn().await;
+
+    let mut req = format!("{}/starwars/ws", app.base.replacen("http", "ws", 1))
+        .into_client_request()
+        .unwrap();
+    req.headers_mut().insert(
+        "sec-websocket-protocol",
+        "graphql-transport-ws".parse().unwrap(),
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+
+    let token = app.login(USER_EMAIL).await;
+    let init = json!({
+        "type": "connection_init",
+        "payload": { "Authorization": format!("Bearer {}", token) }
+    });
+    socket.send(Message::Text(init.to_string())).await.unwrap();
+    assert_eq!(receive(&mut socket).await["type"], "connection_ack");
+
+    let subscribe = json!({
+        "id": "1",
+        "type": "subscribe",
+        "payload": {
+            "query": "subscription { characterChanged(kind: CREATED) { kind character { name } } }"
+        }
+    });
+    socket
+        .send(Message::Text(subscribe.to_string()))
+        .await
+        .unwrap();
+
+    // give the server a moment to start the subscription
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    app.graphql_as(
+        Some(&token),
+        r#"mutation { createDroid(input: { name: "BB-8" }) { id } }"#,
+    )
+    .await;
+
+    let event = receive(&mut socket).await;
+    assert_eq!(event["type"], "next");
+    assert_eq!(event["id"], "1");
+    assert_eq!(
+        event["payload"]["data"]["characterChanged"],
+        json!({ "kind": "CREATED", "character": { "name": "BB-8" } })
+    );
+}
+
+#[tokio::test]
+async fn starwars_subscription_needs_a_token() {
+    let app = TestApp::spawn().await;
+
+    let mut req = format!("{}/starwars/ws", app.base.replacen("http", "ws", 1))
+        .into_client_request()
+        .unwrap();
+    req.headers_mut().insert(
+        "sec-websocket-protocol",
+        "graphql-transport-ws".parse().unwrap(),
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+
+    let init = json!({ "type": "connection_init" });
+    socket.send(Message::Text(init.to_string())).await.unwrap();
+    assert_eq!(receive(&mut socket).await["type"], "connection_ack");
+
+    let subscribe = json!({
+        "id": "1",
+        "type": "subscribe",
+        "payload": { "query": "subscription { characterChanged { kind } }" }
+    });
+    socket
+        .send(Message::Text(subscribe.to_string()))
+        .await
+        .unwrap();
+
+    let event = receive(&mut socket).await;
+    assert_eq!(event["type"], "next");
+    assert_eq!(
+        event["payload"]["errors"][0]["extensions"]["code"],
+        "UNAUTHORIZED"
+    );
+}
+
+#[tokio::test]
+async fn starwars_ws_takes_only_subscriptions() {
+    let app = TestApp::spawn().await;
+
+    let mut req = format!("{}/starwars/ws", app.base.replacen("http", "ws", 1))
+        .into_client_request()
+        .unwrap();
+    req.headers_mut().insert(
+        "sec-websocket-protocol",
+        "graphql-transport-ws".parse().unwrap(),
+    );
+    let (mut socket, _) = tokio_tungstenite::connect_async(req).await.unwrap();
+
+    let init = json!({ "type": "connection_init" });
+    socket.send(Message::Text(init.to_string())).await.unwrap();
+    assert_eq!(receive(&mut socket).await["type"], "connection_ack");
+
+    // it would run without the timeout of /starwars
+    let query = json!({
+        "id": "1",
+        "type": "subscribe",
+        "payload": { "query": "{ hero { name } }" }
+    });
+    socket.send(Message::Text(query.to_string())).await.unwrap();
+
+    match socket.next().await {
+        Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {}
+        msg => panic!("expected the connection to close, got {:?}", msg),
+    }
+}
+
+#[tokio::test]
+async fn starwars_ws_needs_an_upgrade() {
+    let app = TestApp::spawn().await;
+
+    let res = app
+        .client
+        .get(&app.url("/starwars/ws"))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn graphql_users_need_a_token() {
+    let app = TestApp::spawn().await;
+
+    let body = app.graphql("{ users { edges { node { email } } } }").await;
+
+    assert_eq!(body["errors"][0]["extensions"]["code"], "UNAUTHORIZED");
+}
+
+#[tokio::test]
+async fn graphql_users_connection() {
+    let app = TestApp::spawn().await;
+    let token = app.login(USER_EMAIL).await;
+
+    let body = app
+        .graphql_as(
+            Some(&token),
+            "{ users(first: 1) { edges { cursor node { email role } } pageInfo { hasNextPage } } }",
+        )
+        .await;
+    let users = &body["data"]["users"];
+    assert_eq!(users["edges"][0]["node"]["email"], ADMIN_EMAIL);
+    assert_eq!(users["edges"][0]["node"]["role"], "ADMIN");
+    assert_eq!(users["pageInfo"]["hasNextPage"], true);
+
+    let after = users["edges"][0]["cursor"].as_str().unwrap();
+    let body = app
+        .graphql_as(
+            Some(&token),
+            &format!(
+                r#"{{ users(first: 5, after: "{}") {{ edges {{ node {{ email }} }} pageInfo {{ hasNextPage }} }} }}"#,
+                after
+            ),
+        )
+        .await;
+    let users = &body["data"]["users"];
+    assert_eq!(users["edges"], json!([{ "node": { "email": USER_EMAIL } }]));
+    assert_eq!(users["pageInfo"]["hasNextPage"], false);
+}
+
+#[tokio::test]
+async fn graphql_users_with_an_out_of_range_cursor() {
+    let app = TestApp::spawn().await;
+    let token = app.login(USER_EMAIL).await;
+
+    for after in &[usize::MAX, usize::MAX - 1] {
+        let body = app
+            .graphql_as(
+                Some(&token),
+                &format!(
+                    r#"{{ users(after: "{}") {{ edges {{ node {{ email }} }} }} }}"#,
+                    after
+                ),
+            )
+            .await;
+        assert_eq!(body["errors"][0]["message"], "invalid cursor");
+    }
+}
+
+#[tokio::test]
+async fn graphql_users_by_id() {
+    let app = TestApp::spawn().await;
+    let query =
+        "{ admin: user(id: 1) { email } user: user(id: 2) { email } missing: user(id: 99) { email } }";
+
+    let body = app.graphql(query).await;
+    assert_eq!(body["errors"][0]["extensions"]["code"], "UNAUTHORIZED");
+
+    let token = app.login(USER_EMAIL).await;
+    let body = app.graphql_as(Some(&token), query).await;
+    assert_eq!(
+        body["data"],
+        json!({
+            "admin": { "email": ADMIN_EMAIL },
+            "user": { "email": USER_EMAIL },
+            "missing": null
+        })
+    );
+}
+
+#[tokio::test]
+async fn graphql_create_user() {
+    let app = TestApp::spawn().await;
+    let mutation = r#"mutation {
+        createUser(input: {
+            firstName: "Han", lastName: "Solo", email: "HAN@example.com", password: "correct horse battery"
+        }) { id email role }
+    }"#;
+
+    let token = app.login(USER_EMAIL).await;
+    let body = app.graphql_as(Some(&token), mutation).await;
+    assert_eq!(body["errors"][0]["extensions"]["code"], "UNAUTHORIZED");
+
+    let token = app.login(ADMIN_EMAIL).await;
+    let body = app.graphql_as(Some(&token), mutation).await;
+    let user = &body["data"]["createUser"];
+    assert_eq!(user["email"], "han@example.com");
+    assert_eq!(user["role"], "USER");
+
+    let body = app
+        .graphql(&format!("{{ user(id: {}) {{ firstName }} }}", user["id"]))
+        .await;
+    assert_eq!(body["data"]["user"]["firstName"], "Han");
+
+    let body = app.graphql_as(Some(&token), mutation).await;
+    assert_eq!(body["errors"][0]["extensions"]["code"], "CONFLICT");
+    assert_eq!(body["errors"][0]["extensions"]["field"], "email");
+}
+
+#[tokio::test]
+async fn starwars_query_too_deep() {
+    let app = TestApp::spawn().await;
+
+    let body = app
+        .graphql(
+            r#"{ hero(episode: JEDI) {
+                friends { friends { friends { friends { friends { friends { name } } } } } }
+            } }"#,
+        )
+        .await;
+
+    assert_eq!(body["data"], Value::Null);
+    let message = body["errors"][0]["message"].as_str().unwrap();
+    assert!(message.contains("too deep"), "{}", message);
+}
+
+#[tokio::test]
+async fn starwars_query_too_complex() {
+    let app = TestApp::spawn().await;
+
+    // within the depth limit, but every page multiplies the friends below it
+    let body = app
+        .graphql("{ humans { edges { node { friends { name } } } } droids(first: 1) { edges { node { name } } } }")
+        .await;
+    assert_eq!(body["data"], Value::Null);
+    let message = body["errors"][0]["message"].as_str().unwrap();
+    assert!(message.contains("too complex"), "{}", message);
+
+    let body = app
+        .graphql("{ humans(first: 2) { edges { node { friends { name } } } } }")
+        .await;
+    assert!(body.get("errors").is_none(), "{}", body);
+}
+
+#[tokio::test]
+async fn starwars_huge_nested_pages_are_too_complex() {
+    let mut config = test_config();
+    config.graphql.max_depth = 20;
+    let app = TestApp::spawn_with(config).await;
+
+    // the pages the client asks for multiply, but what they are charged stays in range
+    let body = app
+        .graphql(
+            "{ search(first: 2147483647) { edges { node { films { characters(first: 2147483647) { edges { node { name } } } } } } } }",
+        )
+        .await;
+
+    assert_eq!(body["data"], Value::Null);
+    let message = body["errors"][0]["message"].as_str().unwrap();
+    assert!(message.contains("too complex"), "{}", message);
+}
+
+#[tokio::test]
+async fn starwars_pages_are_capped() {
+    let app = TestApp::spawn().await;
+
+    let body = app
+        .graphql("{ humans(first: 2147483647) { edges { node { name } } } }")
+        .await;
+
+    assert!(body.get("errors").is_none(), "{}", body);
+    let humans = body["data"]["humans"]["edges"].as_array().unwrap();
+    assert!(!humans.is_empty());
+}
+
+fn sha256(query: &str) -> String {
+    format!("{:x}", Sha256::digest(query.as_bytes()))
+}
+
+fn persisted(hash: &str) -> Value {
+    json!({ "persistedQuery": { "version": 1, "sha256Hash": hash } })
+}
+
+#[tokio::test]
+async fn starwars_automatic_persisted_query() {
+    let app = TestApp::spawn().await;
+    let query = "{ hero(episode: NEW_HOPE) { name } }";
+    let hash = sha256(query);
+
+    let body = app
+        .graphql_body(json!({ "extensions": persisted(&hash) }))
+        .await;
+    assert_eq!(body["errors"][0]["message"], "PersistedQueryNotFound");
+    assert_eq!(
+        body["errors"][0]["extensions"]["code"],
+        "PERSISTED_QUERY_NOT_FOUND"
+    );
+
+    let body = app
+        .graphql_body(json!({ "query": query, "extensions": persisted(&hash) }))
+        .await;
+    assert_eq!(body["data"]["hero"]["name"], "R2-D2");
+
+    let body = app
+        .graphql_body(json!({ "extensions": persisted(&hash) }))
+        .await;
+    assert_eq!(body["data"]["hero"]["name"], "R2-D2");
+
+    let body = app
+        .graphql_body(
+            json!({ "query": "{ droids { edges { cursor } } }", "extensions": persisted(&hash) }),
+        )
+        .await;
+    assert_eq!(body["errors"][0]["extensions"]["code"], "BAD_USER_INPUT");
+}
+
+#[tokio::test]
+async fn starwars_allowlist() {
+    let dir = std::env::temp_dir().join(format!("allowlist-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("hero.graphql"),
+        "{ hero(episode: NEW_HOPE) { name } }\n",
+    )
+    .unwrap();
+    std::fs::write(dir.join("README.md"), "{ droids { edges { cursor } } }").unwrap();
+
+    let mut config = test_config();
+    config.graphql.allowlist_dir = Some(dir.clone());
+    let app = TestApp::spawn_with(config).await;
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let body = app.graphql("{ hero(episode: NEW_HOPE) { name } }").await;
+    assert_eq!(body["data"]["hero"]["name"], "R2-D2");
+
+    let hash = sha256("{ hero(episode: NEW_HOPE) { name } }");
+    let body = app
+        .graphql_body(json!({ "extensions": persisted(&hash) }))
+        .await;
+    assert_eq!(body["data"]["hero"]["name"], "R2-D2");
+
+    // what codegen sends, the hash of the file as it is on disk
+    let file = "{ hero(episode: NEW_HOPE) { name } }\n";
+    let body = app
+        .graphql_body(json!({ "extensions": persisted(&sha256(file)) }))
+        .await;
+    assert_eq!(body["data"]["hero"]["name"], "R2-D2");
+    let body = app
+        .graphql_body(json!({ "query": file, "extensions": persisted(&sha256(file)) }))
+        .await;
+    assert_eq!(body["data"]["hero"]["name"], "R2-D2");
+
+    let body = app.graphql("{ droids { edges { cursor } } }").await;
+    assert_eq!(body["data"], Value::Null);
+    assert_eq!(body["errors"][0]["extensions"]["code"], "QUERY_NOT_ALLOWED");
+
+    let body = app
+        .graphql_body(
+            json!({ "extensions": persisted(&sha256("{ droids { edges { cursor } } }")) }),
+        )
+        .await;
+    assert_eq!(
+        body["errors"][0]["extensions"]["code"],
+        "PERSISTED_QUERY_NOT_FOUND"
+    );
+}
@@ -0,0 +1,23 @@
+use example_heroku_darpi::config::Config;
+
+// the only test in this binary, nothing else reads the environment it changes
+#[test]
+fn zero_sizes_and_expiries_are_rejected() {
+    std::env::set_var("STORAGE", "memory");
+    std::env::set_var("PORT", "3000");
+    std::env::set_var("JWT_SECRET", "test secret");
+    std::env::set_var("JWT_EXPIRY_SECS", "0");
+    std::env::set_var("JWT_REFRESH_EXPIRY_SECS", "-60");
+    std::env::set_var("GRAPHQL_APQ_CACHE_SIZE", "0");
+
+    let problems = Config::load().unwrap_err().to_string();
+
+    for (key, var) in &[
+        ("jwt.expiry_secs", "JWT_EXPIRY_SECS"),
+        ("jwt.refresh_expiry_secs", "JWT_REFRESH_EXPIRY_SECS"),
+        ("graphql.apq_cache_size", "GRAPHQL_APQ_CACHE_SIZE"),
+    ] {
+        let problem = format!("`{}` from env var {} must be greater than 0", key, var);
+        assert!(problems.contains(&problem), "{}", problems);
+    }
+}
@@ -0,0 +1,94 @@
+use darpi::chrono::Duration;
+use example_heroku_darpi::config::{JwtConfig, JwtKeyConfig};
+use example_heroku_darpi::jwt::{Claims, JwtKeys, JwtKeysImpl, JwtKeysImplParameters, KeySet};
+use example_heroku_darpi::middleware::Role;
+use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+use serde_json::json;
+use shaku::{module, HasComponent};
+
+module! {
+    Keys {
+        components = [JwtKeysImpl],
+        providers = []
+    }
+}
+
+// signs with ES256 and still accepts the RS256 key it rotated away from,
+// which sits in tests/keys/previous as `2020.RS256.pem`
+fn keys() -> Keys {
+    let config = JwtConfig {
+        algorithm: Algorithm::ES256,
+        key_id: "2021".to_string(),
+        keys: JwtKeyConfig::Pem {
+            private_key: "tests/keys/es256-private.pem".into(),
+            public_key: "tests/keys/es256-public.pem".into(),
+            previous_keys_dir: Some("tests/keys/previous".into()),
+        },
+        expiry: Duration::minutes(5),
+        refresh_expiry: Duration::days(1),
+    };
+    let keys = KeySet::from_config(&config).expect("test keys");
+
+    Keys::builder()
+        .with_component_parameters::<JwtKeysImpl>(JwtKeysImplParameters {
+            keys,
+            expiry: config.expiry,
+            refresh_expiry: config.refresh_expiry,
+        })
+        .build()
+}
+
+#[test]
+fn tokens_of_the_previous_algorithm_still_verify() {
+    let keys = keys();
+    let jwt: &dyn JwtKeys = keys.resolve_ref();
+
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("2020".to_string());
+    let old_key = EncodingKey::from_rsa_pem(include_bytes!("keys/rs256-private.pem")).unwrap();
+    let claims = Claims::new("1", Role::User, Duration::minutes(5));
+    let token = encode(&header, &claims, &old_key).unwrap();
+    assert_eq!(jwt.verify(&token).unwrap().sub, "1");
+
+    let token = jwt
+        .sign(&Claims::new("2", Role::Admin, Duration::minutes(5)))
+        .unwrap();
+    assert_eq!(jwt.verify(&token).unwrap().sub, "2");
+}
+
+#[test]
+fn a_token_must_use_the_algorithm_of_its_key() {
+    let keys = keys();
+    let jwt: &dyn JwtKeys = keys.resolve_ref();
+
+    // the old RSA key, but claiming the current key id
+    let mut header = Header::new(Algorithm::RS256);
+    header.kid = Some("2021".to_string());
+    let old_key = EncodingKey::from_rsa_pem(include_bytes!("keys/rs256-private.pem")).unwrap();
+    let claims = Claims::new("1", Role::User, Duration::minutes(5));
+    let token = encode(&header, &claims, &old_key).unwrap();
+
+    assert!(jwt.verify(&token).is_err());
+}
+
+#[test]
+fn the_jwks_has_every_key_with_its_algorithm() {
+    let keys = keys();
+    let jwt: &dyn JwtKeys = keys.resolve_ref();
+
+    let mut published: Vec<_> = serde_json::to_value(jwt.jwks()).unwrap()["keys"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|key| json!([key["kid"], key["alg"], key["kty"]]))
+        .collect();
+    published.sort_by_key(|key| key.to_string());
+
+    assert_eq!(
+        published,
+        [
+            json!(["2020", "RS256", "RSA"]),
+            json!(["2021", "ES256", "EC"])
+        ]
+    );
+}
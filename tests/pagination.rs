@@ -0,0 +1,111 @@
+use async_graphql::connection::CursorType;
+use example_heroku_darpi::pagination::{page, OpaqueCursor};
+use proptest::collection::btree_set;
+use proptest::prelude::*;
+
+fn keys() -> impl Strategy<Value = Vec<u32>> {
+    btree_set(0u32..200, 0..40).prop_map(|keys| keys.into_iter().collect())
+}
+
+proptest! {
+    #[test]
+    fn page_stays_within_the_cursors(
+        keys in keys(),
+        after in proptest::option::of(0u32..200),
+        before in proptest::option::of(0u32..200),
+        first in proptest::option::of(0usize..50),
+        last in proptest::option::of(0usize..50),
+    ) {
+        let page = page(&keys, after.as_ref(), before.as_ref(), first, last);
+
+        prop_assert!(page.start <= page.end && page.end <= keys.len());
+        if let Some(limit) = first.or(last) {
+            prop_assert!(page.end - page.start <= limit);
+        }
+        for key in &keys[page.start..page.end] {
+            prop_assert!(after.is_none_or(|after| *key > after));
+            prop_assert!(before.is_none_or(|before| *key < before));
+        }
+    }
+
+    #[test]
+    fn first_takes_the_first_items_after_the_cursor(
+        keys in keys(),
+        after in proptest::option::of(0u32..200),
+        first in 0usize..50,
+    ) {
+        let page = page(&keys, after.as_ref(), None, Some(first), None);
+
+        let expected: Vec<_> = keys
+            .iter()
+            .filter(|key| after.is_none_or(|after| **key > after))
+            .take(first)
+            .collect();
+        let actual: Vec<_> = keys[page.start..page.end].iter().collect();
+        prop_assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn last_takes_the_last_items_before_the_cursor(
+        keys in keys(),
+        before in proptest::option::of(0u32..200),
+        last in 0usize..50,
+    ) {
+        let page = page(&keys, None, before.as_ref(), None, Some(last));
+
+        let mut expected: Vec<_> = keys
+            .iter()
+            .rev()
+            .filter(|key| before.is_none_or(|before| **key < before))
+            .take(last)
+            .collect();
+        expected.reverse();
+        let actual: Vec<_> = keys[page.start..page.end].iter().collect();
+        prop_assert_eq!(actual, expected);
+    }
+
+    #[test]
+    fn paging_forward_visits_every_item_once(keys in keys(), size in 1usize..10) {
+        let mut seen = vec![];
+        let mut after = None;
+        loop {
+            let page = page(&keys, after.as_ref(), None, Some(size), None);
+            seen.extend_from_slice(&keys[page.start..page.end]);
+            if !page.has_next_page {
+                break;
+            }
+            prop_assert_eq!(page.end - page.start, size);
+            after = Some(keys[page.end - 1]);
+        }
+        prop_assert_eq!(seen, keys);
+    }
+
+    #[test]
+    fn paging_backward_visits_every_item_once(keys in keys(), size in 1usize..10) {
+        let mut seen = vec![];
+        let mut before = None;
+        loop {
+            let page = page(&keys, None, before.as_ref(), None, Some(size));
+            seen.splice(0..0, keys[page.start..page.end].iter().copied());
+            if !page.has_previous_page {
+                break;
+            }
+            prop_assert_eq!(page.end - page.start, size);
+            before = Some(keys[page.start]);
+        }
+        prop_assert_eq!(seen, keys);
+    }
+
+    #[test]
+    fn cursors_round_trip(key in ".*") {
+        let cursor = OpaqueCursor(key);
+        let decoded = OpaqueCursor::decode_cursor(&cursor.encode_cursor());
+        prop_assert_eq!(decoded.ok(), Some(cursor));
+    }
+}
+
+#[test]
+fn cursors_must_be_ours() {
+    assert!(OpaqueCursor::decode_cursor("not base64!").is_err());
+    assert!(OpaqueCursor::decode_cursor(&base64::encode("1000")).is_err());
+}
@@ -0,0 +1,55 @@
+use example_heroku_darpi::config::GraphqlConfig;
+use example_heroku_darpi::starwars::{make_schema, sdl};
+use std::path::Path;
+
+// the same file `cargo run -- sdl` writes
+const SNAPSHOT: &str = "schema.graphql";
+
+// a change to the schema has to show up in the diff of the snapshot
+// run with UPDATE_SCHEMA=1 to accept it, a missing snapshot fails like a changed one
+#[test]
+fn the_schema_is_the_snapshot() {
+    let actual = sdl();
+    let path = Path::new(SNAPSHOT);
+
+    if std::env::var_os("UPDATE_SCHEMA").is_some() {
+        std::fs::write(path, &actual).unwrap();
+        eprintln!("recorded {}, check it in", SNAPSHOT);
+        return;
+    }
+
+    let expected = std::fs::read_to_string(path).unwrap_or_else(|e| {
+        panic!(
+            "can not read {}: {}, run the tests with UPDATE_SCHEMA=1 to record it",
+            SNAPSHOT, e
+        )
+    });
+    assert!(
+        actual == expected,
+        "the schema changed, run the tests with UPDATE_SCHEMA=1 if that was intended\n{}",
+        actual
+    );
+}
+
+fn config(introspection: bool) -> GraphqlConfig {
+    GraphqlConfig {
+        max_depth: 10,
+        max_complexity: 1000,
+        timeout: std::time::Duration::from_secs(5),
+        apq_cache_size: 16,
+        allowlist_dir: None,
+        introspection,
+        playground: false,
+    }
+}
+
+#[tokio::test]
+async fn introspection_can_be_disabled() {
+    let query = "{ __schema { queryType { name } } }";
+
+    let res = make_schema(&config(true)).execute(query).await;
+    assert!(res.errors.is_empty(), "{:?}", res.errors);
+
+    let res = make_schema(&config(false)).execute(query).await;
+    assert!(!res.errors.is_empty());
+}
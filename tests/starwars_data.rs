@@ -0,0 +1,116 @@
+use example_heroku_darpi::repository::CharacterRepository;
+use example_heroku_darpi::starwars::{CharacterKind, StarWars};
+use std::path::{Path, PathBuf};
+
+// a data file of its own for every test, they run in parallel
+fn data_file(name: &str, content: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("starwars-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, content).unwrap();
+    path
+}
+
+async fn ids(starwars: &StarWars) -> Vec<String> {
+    let mut ids = vec![];
+    for &kind in &[CharacterKind::Human, CharacterKind::Droid] {
+        match starwars.ids(kind).await {
+            Ok(found) => ids.extend(found),
+            Err(e) => panic!("{}", e),
+        }
+    }
+    ids
+}
+
+#[tokio::test]
+async fn loads_the_shipped_dataset() {
+    let starwars = StarWars::load(Path::new("data/starwars.json")).unwrap();
+    assert_eq!(
+        ids(&starwars).await,
+        ["1000", "1001", "1002", "1003", "1004", "2000", "2001"]
+    );
+}
+
+#[tokio::test]
+async fn loads_yaml() {
+    let path = data_file(
+        "yaml.yaml",
+        r#"
+hero: "1"
+characters:
+  - id: "1"
+    name: Ackbar
+    kind: human
+    appearsIn: [JEDI]
+    friends: ["2"]
+  - id: "2"
+    name: R5-D4
+    kind: droid
+"#,
+    );
+
+    let starwars = StarWars::load(&path).unwrap();
+    assert_eq!(ids(&starwars).await, ["1", "2"]);
+}
+
+#[test]
+fn reports_every_problem() {
+    let path = data_file(
+        "invalid.json",
+        r#"{
+            "hero": "9",
+            "characters": [
+                { "id": "1", "name": " ", "kind": "human", "friends": ["7"] },
+                { "id": "1", "name": "Jabba", "kind": "human", "primaryFunction": "Crime" },
+                { "id": "2", "name": "IG-88", "kind": "droid", "homePlanet": "Halowan", "friends": ["2"] }
+            ]
+        }"#,
+    );
+
+    let problems = StarWars::load(&path).unwrap_err().to_string();
+    for problem in &[
+        "`1`: name must not be empty",
+        "`1`: the id is taken by another character",
+        "`1`: humans have no primary function",
+        "`2`: droids have no home planet",
+        "`2`: a character can not befriend itself",
+        "`1`: no character with id `7`",
+        "hero: no character with id `9`",
+    ] {
+        assert!(problems.contains(problem), "{} in {}", problem, problems);
+    }
+}
+
+#[test]
+fn rejects_unknown_episodes() {
+    let path = data_file(
+        "episode.json",
+        r#"{
+            "hero": "1",
+            "characters": [{ "id": "1", "name": "Rey", "kind": "human", "appearsIn": ["FORCE_AWAKENS"] }]
+        }"#,
+    );
+
+    let problems = StarWars::load(&path).unwrap_err().to_string();
+    assert!(problems.contains("FORCE_AWAKENS"), "{}", problems);
+}
+
+#[tokio::test]
+async fn reload_swaps_the_dataset() {
+    let one = r#"{ "hero": "1", "characters": [{ "id": "1", "name": "Biggs", "kind": "human" }] }"#;
+    let two = r#"{ "hero": "2", "characters": [{ "id": "2", "name": "Wedge", "kind": "human" }] }"#;
+    let path = data_file("reload.json", one);
+
+    let starwars = StarWars::load(&path).unwrap();
+    // a clone is a handle to the same data, like the one the container holds
+    let schema_data = starwars.clone();
+
+    std::fs::write(&path, two).unwrap();
+    starwars.reload(&path).unwrap();
+    assert_eq!(ids(&schema_data).await, ["2"]);
+
+    // a broken file leaves the current dataset alone
+    std::fs::write(&path, "{").unwrap();
+    assert!(starwars.reload(&path).is_err());
+    assert_eq!(ids(&schema_data).await, ["2"]);
+}